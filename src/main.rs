@@ -1,306 +1,7701 @@
 use axum::{
-    body::Bytes,
-    extract::Query,
-    http::StatusCode,
-    response::Json as ResponseJson,
+    body::{Body, Bytes},
+    extract::{FromRequest, Multipart, Path, Query, RawQuery, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json as ResponseJson, Response,
+    },
     routing::post,
     Router,
 };
 use serde::{Deserialize, Serialize};
-use tokio;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
 use tower::ServiceBuilder;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::{Any, CorsLayer};
-use chrono::{DateTime, Utc, NaiveDateTime, TimeZone};
+use chrono::{DateTime, FixedOffset, Utc, NaiveDateTime, TimeZone};
 use reqwest::Client;
+use tracing::{debug, error, info, warn};
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 struct QueryParams {
     device_id: Option<String>,
+    // Optional user tag; also accepted as the X-User-Id header
+    user_id: Option<String>,
+    // Include a histogram of the processed values in the response
+    #[serde(default)]
+    histogram: bool,
+    // Overrides HISTOGRAM_BUCKET_WIDTH for this request
+    bucket_width: Option<f64>,
+    // Store each record's source UTC offset as an `offset_minutes` field
+    #[serde(default)]
+    store_offset: bool,
+    // Outlier filter, e.g. `median:5` (drop spikes) or `mean:5` (smooth)
+    filter: Option<String>,
+    // Write the change from the previous reading as a `delta` field
+    #[serde(default)]
+    with_delta: bool,
+    // Nudge records with identical timestamps apart instead of letting them overwrite each other
+    #[serde(default)]
+    disambiguate_dupes: bool,
+    // Echo the processed records back in the response
+    #[serde(default)]
+    return_records: bool,
+    // Response format for returned records: json (default) or csv
+    format: Option<String>,
+    // Write to a per-device measurement (heart_rate_<device>); defaults to MEASUREMENT_PER_DEVICE
+    measurement_per_device: Option<bool>,
+    // Measurement (table) to write to instead of heart_rate; also the per-device prefix
+    measurement: Option<String>,
+    // Extra tags written on every line, e.g. `tags=site:berlin,activity:run`; at most MAX_TAGS
+    tags: Option<String>,
+    // Read `72,5` as 72.5 (comma decimal separator)
+    #[serde(default)]
+    decimal_comma: bool,
+    // Write each value's 1-based line number in the body as a `source_line` field, for debugging pairing
+    #[serde(default)]
+    with_source_line: bool,
+    // Write the time the proxy received the request as an `ingested_at` field (epoch ms),
+    // to study late-arriving data
+    #[serde(default)]
+    with_ingest_time: bool,
+    // Build the GreptimeDB request and return it instead of sending it
+    #[serde(default)]
+    dry_run: bool,
+    // Answer 207 instead of 200 when records were written but some were dropped or warned about;
+    // defaults to MULTI_STATUS_ON_WARNINGS
+    multi_status: Option<bool>,
+    // Write each UTC calendar day as its own batch so a failure only loses that day
+    #[serde(default)]
+    split_by_day: bool,
+    // Force how values pair with timestamps: sequential (default), alternating, values_first,
+    // timestamps_first or proportional
+    strategy: Option<String>,
+    // Tag each reading with its heart-rate zone (rest, fat_burn, cardio, peak)
+    #[serde(default)]
+    with_zone: bool,
+    // `sort=false` keeps records in source order instead of sorting by timestamp.
+    // GreptimeDB accepts unsorted writes, so this only affects order in responses and logs.
+    sort: Option<bool>,
+    // Write precision for this request (s, ms, us, ns); defaults to WRITE_PRECISION
+    precision: Option<String>,
+    // For bodies with values but no timestamps: space the values `interval_secs` apart,
+    // the last one at `end_time` (RFC 3339 or YYYY-MM-DD, default now)
+    #[serde(default)]
+    synthesize_ts: bool,
+    interval_secs: Option<u64>,
+    end_time: Option<String>,
+    // Only honored when ALLOW_QUERY_TOKEN is set; never logged
+    token: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+// Keys of QueryParams, checked against the raw query when STRICT_QUERY_PARAMS is set
+const QUERY_PARAM_KEYS: &[&str] = &[
+    "device_id", "user_id", "histogram", "bucket_width", "store_offset", "filter", "with_delta",
+    "disambiguate_dupes", "return_records", "format", "measurement_per_device", "measurement", "tags", "dry_run", "multi_status",
+    "split_by_day", "strategy", "sort", "with_zone", "precision", "synthesize_ts", "interval_secs",
+    "end_time", "decimal_comma", "with_source_line", "with_ingest_time", "token",
+];
+
+// QueryParams keys /heart-rate/stream rejects: they shape a single response or write, which a
+// stream of batch events doesn't have
+const STREAM_UNSUPPORTED_PARAM_KEYS: &[&str] = &[
+    "histogram", "bucket_width", "filter", "with_delta", "disambiguate_dupes", "return_records", "format",
+    "dry_run", "multi_status", "split_by_day",
+];
+
+#[derive(Debug, Default, Serialize)]
 struct ApiResponse {
     success: bool,
     message: String,
     processed_count: usize,
+    // Records parsed but not written (e.g. oversized lines)
+    dropped_count: usize,
+    // Records removed or smoothed by the outlier filter
+    #[serde(skip_serializing_if = "is_zero")]
+    filtered_count: usize,
+    // Values matching a GAP_SENTINELS "no reading" marker
+    #[serde(skip_serializing_if = "is_zero")]
+    gap_count: usize,
+    // Timestamps that lost sub-unit detail to the configured write precision
+    #[serde(skip_serializing_if = "is_zero")]
+    truncated_count: usize,
+    // Records older than MAX_AGE_DAYS (also counted in dropped_count)
+    #[serde(skip_serializing_if = "is_zero")]
+    expired_count: usize,
+    // Samples dropped for an implausible jump from the previous one (also counted in dropped_count)
+    #[serde(skip_serializing_if = "is_zero")]
+    jump_count: usize,
+    // Records skipped because a recent request already wrote them (DEDUP_WINDOW_SECS)
+    #[serde(skip_serializing_if = "is_zero")]
+    duplicate_count: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    // Warnings left out past MAX_WARNINGS; nonzero means `warnings` was truncated
+    #[serde(skip_serializing_if = "is_zero")]
+    omitted_warnings: usize,
+    // Bucketed value counts, only with `histogram=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    histogram: Option<Vec<HistogramBucket>>,
+    // The parsed records, only with `return_records=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    records: Option<Vec<RecordOutput>>,
+    // The request that would have been sent, only with `dry_run=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dry_run: Option<WritePreview>,
+    // Per-day write results, only with `split_by_day=true`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    days: Vec<DayResult>,
+}
+
+impl ApiResponse {
+    // Keep the first `max` warnings, counting the rest in omitted_warnings
+    fn cap_warnings(mut self, max: usize) -> Self {
+        if self.warnings.len() > max {
+            self.omitted_warnings += self.warnings.len() - max;
+            self.warnings.truncate(max);
+        }
+        self
+    }
+}
+
+// Outcome of one day's batch under `split_by_day=true`
+#[derive(Debug, Serialize)]
+struct DayResult {
+    date: String,
+    processed_count: usize,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// A processed record as echoed back to the client
+#[derive(Debug, Serialize)]
+struct RecordOutput {
+    timestamp: String,
+    value: f64,
+    offset_minutes: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confidence: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_line: Option<usize>,
+}
+
+impl From<&HeartRateRecord> for RecordOutput {
+    fn from(record: &HeartRateRecord) -> Self {
+        Self {
+            timestamp: record.timestamp.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+            value: record.value,
+            offset_minutes: record.offset_minutes,
+            delta: record.delta,
+            confidence: record.confidence,
+            source_line: record.source_line,
+        }
+    }
+}
+
+// Return processed records as a downloadable CSV file
+fn records_csv_response(records: &[HeartRateRecord]) -> Response {
+    let mut csv = String::from("timestamp,value,offset_minutes,delta,confidence,source_line\n");
+    for record in records.iter().map(RecordOutput::from) {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            record.timestamp,
+            record.value,
+            record.offset_minutes,
+            record.delta.map(|delta| delta.to_string()).unwrap_or_default(),
+            record.confidence.map(|confidence| confidence.to_string()).unwrap_or_default(),
+            record.source_line.map(|line| line.to_string()).unwrap_or_default()
+        ));
+    }
+
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"heart_rate.csv\""),
+        ],
+        csv,
+    )
+        .into_response()
+}
+
+fn is_zero(count: &usize) -> bool {
+    *count == 0
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct HistogramBucket {
+    // Bucket covers [start, end)
+    start: f64,
+    end: f64,
+    count: usize,
+}
+
+#[derive(Deserialize)]
+struct DeleteParams {
+    // RFC 3339 time range, start inclusive and end exclusive
+    start: String,
+    end: String,
+    // Measurement the device was written to (default heart_rate); its per-device table is covered too
+    measurement: Option<String>,
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeleteResponse {
+    success: bool,
+    message: String,
+    deleted_count: u64,
 }
 
 #[derive(Debug, Clone)]
-struct AppState {
+struct Config {
     greptime_url: String,
+    // Optional backup instance receiving a best-effort copy of every write
+    greptime_mirror_url: Option<String>,
+    // Mirror writes allowed to run at once (MAX_MIRROR_WRITES); more are dropped and counted
+    max_mirror_writes: usize,
+    // Target database, either `schema` or `catalog-schema` for a non-default catalog
     greptime_db: String,
-    http_client: Client,
+    // Basic auth credentials for GreptimeDB (GREPTIME_USERNAME, GREPTIME_PASSWORD); none when unset
+    greptime_username: Option<String>,
+    greptime_password: Option<String>,
+    port: u16,
+    // Capacity of the bounded queue between handlers and the flusher
+    write_queue_capacity: usize,
+    // How long a handler waits for queue capacity before giving up with 503
+    enqueue_timeout: Duration,
+    // Upper bound on lines combined into a single GreptimeDB write
+    flush_max_lines: usize,
+    // Hold a flush up to FLUSH_MAX_WAIT_MS while fewer than FLUSH_MIN_RECORDS lines are queued,
+    // trading latency for fewer writes; 0 flushes as soon as anything is queued
+    flush_min_records: usize,
+    flush_max_wait: Duration,
+    // Round values and write them as line-protocol integers (`72i`)
+    hr_as_integer: bool,
+    // Number of decimals used when writing float values
+    hr_decimals: usize,
+    // Token required on write requests; auth is disabled when unset
+    api_token: Option<String>,
+    // Also accept the token as a `token` query param for clients that can't set headers
+    allow_query_token: bool,
+    // Answer 422 instead of 200 with `success: false` when no valid records remain
+    empty_as_error: bool,
+    // Retries for failed primary writes, with full-jitter exponential backoff
+    write_max_retries: u32,
+    retry_base_delay: Duration,
+    retry_max_delay: Duration,
+    // Time budget for a write and all its retries (WRITE_DEADLINE_MS); no retry starts past it
+    write_deadline: Option<Duration>,
+    // Longest line-protocol line sent to GreptimeDB; longer lines are dropped
+    max_line_length: usize,
+    // JSON file mapping raw device_id to a friendly `device_name` tag
+    device_map_path: Option<String>,
+    // Records with earlier timestamps are rejected (MIN_TS, default 2000-01-01)
+    min_timestamp: DateTime<Utc>,
+    // Default bucket width (BPM) for response histograms
+    histogram_bucket_width: f64,
+    // Timestamp precision for writes (s, ms, us, ns)
+    precision: Precision,
+    // Force HTTP/2 (prior knowledge) for GreptimeDB connections
+    greptime_http2: bool,
+    // TCP / HTTP/2 keep-alive interval; None disables it
+    greptime_keepalive: Option<Duration>,
+    // How long idle pooled connections are kept open
+    greptime_pool_idle_timeout: Option<Duration>,
+    // Total time allowed for one GreptimeDB request (GREPTIME_TIMEOUT_MS); None waits indefinitely
+    greptime_timeout: Option<Duration>,
+    // Create the database via the SQL API when a write finds it missing
+    auto_create_db: bool,
+    // Comma-separated noise prefixes stripped from each input line (STRIP_PREFIXES)
+    strip_prefixes: Vec<String>,
+    // Keys naming the value / timestamp in a JSON body; the first present key wins
+    // (JSON_VALUE_KEYS, JSON_TIME_KEYS)
+    value_keys: Vec<String>,
+    time_keys: Vec<String>,
+    // Labels stripped from text lines like `bpm=72` or `time=...` (VALUE_LABEL_KEYS, TIME_LABEL_KEYS)
+    value_label_keys: Vec<String>,
+    time_label_keys: Vec<String>,
+    // Seconds added to every parsed timestamp (CLOCK_OFFSET_SECS), may be negative;
+    // clamped to ±MAX_CLOCK_OFFSET_SECS
+    clock_offset_secs: i64,
+    // Deviation from the window median (BPM) above which the median filter drops a sample
+    outlier_max_deviation: f64,
+    // Write destination: "greptime" (default) or "file"
+    write_sink: String,
+    // Line-protocol file used by the file sink
+    sink_path: String,
+    // Protocol for GreptimeDB writes: "influxdb" line protocol (default) or "opentsdb" JSON
+    write_backend: String,
+    // HTTP method of line-protocol writes (GREPTIME_WRITE_METHOD): POST (default) or PUT, for gateways requiring PUT
+    write_method: reqwest::Method,
+    // Append batches GreptimeDB rejected to SINK_PATH instead of failing them (FALLBACK_TO_FILE)
+    fallback_to_file: bool,
+    // Prefix for all routes (ROUTE_PREFIX), empty for none
+    route_prefix: String,
+    // Most requests handled at once (MAX_INFLIGHT_REQUESTS), beyond which they get 503; 0 means no limit
+    max_inflight_requests: Option<usize>,
+    // Gzip responses for clients sending `Accept-Encoding: gzip` (COMPRESS_RESPONSES)
+    compress_responses: bool,
+    // Handling of `68-74` range values (HR_RANGE_MODE: off, midpoint, minmax)
+    hr_range_mode: RangeMode,
+    // Case folding of device ids before they are used (DEVICE_ID_CASE: none, lower, upper)
+    device_id_case: DeviceIdCase,
+    // What happens to device ids with characters outside [A-Za-z0-9_-], which could escape a
+    // measurement or file name (DEVICE_ID_POLICY: allow, reject, sanitize)
+    device_id_policy: DeviceIdPolicy,
+    // Plausible heart rates in BPM (MIN_HEART_RATE, MAX_HEART_RATE); other numbers are not values
+    min_heart_rate: f64,
+    max_heart_rate: f64,
+    // Write each device to its own measurement instead of a shared one with a tag
+    measurement_per_device: bool,
+    // Maximum bytes of a GreptimeDB error body that are read and reported
+    error_body_limit: usize,
+    // Lines per write (and per progress event) on /heart-rate/stream
+    stream_batch_size: usize,
+    // Reject requests carrying query parameters the endpoint does not know
+    strict_query_params: bool,
+    // Warn about devices that have not reported for this long; None disables the watchdog
+    silent_device_after: Option<Duration>,
+    // How often the watchdog checks for silent devices
+    watchdog_interval: Duration,
+    // End write bodies with a newline, for targets that require one
+    trailing_newline: bool,
+    // Values exporters use to mean "no reading" (e.g. 0, -1), counted as gaps
+    gap_sentinels: Vec<f64>,
+    // Record a hash and length of every uploaded body in the upload_audit measurement
+    upload_audit: bool,
+    // Also keep the full uploaded text here, one <sha256>.txt file per body
+    upload_audit_blob_dir: Option<String>,
+    // Named timezone (e.g. Europe/Berlin) for naive local timestamps; UTC+8 when unset
+    local_tz: Option<chrono_tz::Tz>,
+    // Timestamp formats tried on each line, in order (TIMESTAMP_FORMATS)
+    timestamp_formats: Vec<TimestampFormat>,
+    // Time allowed for parsing one request body; None means no limit
+    parse_timeout: Option<Duration>,
+    // Body line naming the device, e.g. `^Device:\s*(\S+)$`; the first capture group is the device_id
+    device_line_pattern: Option<regex::Regex>,
+    // Report partial success (records written, some dropped) as 207 Multi-Status
+    multi_status_on_warnings: bool,
+    // Read `72 confidence=0.9` style readings and store the confidence as a field
+    parse_confidence: bool,
+    // Linear transform applied to raw values before range checks, for sensors in scaled units
+    value_transform: ValueTransform,
+    // Probe GreptimeDB with `SELECT 1` at startup so /health/ready can pass before the first write
+    startup_probe: bool,
+    // Media types accepted on /heart-rate, e.g. text/plain, multipart/form-data, text/*; empty allows any
+    allowed_content_types: Vec<String>,
+    // Rolling window: records older than this relative to now are dropped
+    max_age: Option<chrono::Duration>,
+    // Largest plausible heart-rate change in BPM per second between consecutive samples
+    // (MAX_HR_JUMP_PER_SEC); samples changing faster are dropped
+    max_hr_jump_per_sec: Option<f64>,
+    // Zone boundaries used by `with_zone=true`
+    hr_zones: ZoneThresholds,
+    // Mask readings, timestamps and raw body text in logs (see Redact)
+    redact_values: bool,
+    // Most individual warnings returned per response (MAX_WARNINGS); the rest are only counted
+    max_warnings: usize,
+    // Most extra tags a request may add with `tags=` (MAX_TAGS), to bound series cardinality
+    max_tags: usize,
+    // Measurements the `measurement` param may name (ALLOWED_MEASUREMENTS); empty allows any valid name
+    allowed_measurements: Vec<String>,
+    // Message for successful /heart-rate writes, `{count}` replaced by the record count
+    // (SUCCESS_MESSAGE_TEMPLATE); keeps the message stable for clients that parse it
+    success_message_template: Option<String>,
+    // Reject another write for the same device within this window with 429 (WRITE_DEBOUNCE_MS),
+    // to catch accidental double submits; None disables it
+    write_debounce: Option<Duration>,
+    // How long written timestamps are remembered per device to skip re-sent readings; None disables it
+    dedup_window: Option<Duration>,
+    // UTC time of day (ROLLUP_AT, HH:MM) to write the previous day's per-device summary; None disables it
+    rollup_at: Option<chrono::NaiveTime>,
+    // After each rollup, delete raw heart_rate rows older than this many days (RAW_RETENTION_DAYS)
+    raw_retention_days: Option<u32>,
+    // How long shutdown waits for in-flight requests, queued writes and mirror writes to finish
+    shutdown_flush_timeout: Duration,
 }
 
-impl AppState {
-    fn new(greptime_url: String, greptime_db: String) -> Self {
+impl Config {
+    // Read configuration from CONFIG_FILE settings and environment variables
+    fn from_source(source: &ConfigSource) -> Self {
         Self {
-            greptime_url,
-            greptime_db,
-            http_client: Client::new(),
+            greptime_url: source.var("GREPTIME_URL")
+                .unwrap_or_else(|_| "http://127.0.0.1".to_string()),
+            greptime_mirror_url: source.var("GREPTIME_MIRROR_URL").ok().filter(|url| !url.is_empty()),
+            max_mirror_writes: source.or("MAX_MIRROR_WRITES", 64),
+            greptime_db: source.var("GREPTIME_DB")
+                .unwrap_or_else(|_| "heartbeat_test".to_string()),
+            greptime_username: source.var("GREPTIME_USERNAME").ok().filter(|username| !username.is_empty()),
+            greptime_password: source.var("GREPTIME_PASSWORD").ok(),
+            port: source.or("PORT", 3000),
+            write_queue_capacity: source.or("WRITE_QUEUE_CAPACITY", 64).max(1),
+            enqueue_timeout: Duration::from_millis(source.or("ENQUEUE_TIMEOUT_MS", 5000)),
+            flush_max_lines: source.or("FLUSH_MAX_LINES", 5000).max(1),
+            flush_min_records: source.or("FLUSH_MIN_RECORDS", 0),
+            flush_max_wait: Duration::from_millis(source.or("FLUSH_MAX_WAIT_MS", 0)),
+            hr_as_integer: source.flag("HR_AS_INTEGER"),
+            hr_decimals: source.or("HR_DECIMALS", 2),
+            api_token: source.var("API_TOKEN").ok().filter(|token| !token.is_empty()),
+            allow_query_token: source.flag("ALLOW_QUERY_TOKEN"),
+            empty_as_error: source.flag("EMPTY_AS_ERROR"),
+            write_max_retries: source.or("WRITE_MAX_RETRIES", 3),
+            retry_base_delay: Duration::from_millis(source.or("RETRY_BASE_MS", 200)),
+            retry_max_delay: Duration::from_millis(source.or("RETRY_MAX_MS", 5000)),
+            write_deadline: Some(source.or("WRITE_DEADLINE_MS", 0))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            max_line_length: source.or("MAX_LINE_LENGTH", 4096),
+            device_map_path: source.var("DEVICE_MAP_PATH").ok().filter(|path| !path.is_empty()),
+            min_timestamp: source.var("MIN_TS")
+                .ok()
+                .and_then(|value| parse_config_time(&value))
+                .unwrap_or_else(|| Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap()),
+            histogram_bucket_width: source.or("HISTOGRAM_BUCKET_WIDTH", 10.0),
+            precision: source.var("WRITE_PRECISION")
+                .ok()
+                .and_then(|value| Precision::parse(&value))
+                .unwrap_or_default(),
+            greptime_http2: source.flag("GREPTIME_HTTP2"),
+            greptime_keepalive: Some(source.or("GREPTIME_KEEPALIVE_SECS", 60))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            greptime_pool_idle_timeout: Some(source.or("GREPTIME_POOL_IDLE_SECS", 90))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            greptime_timeout: Some(source.or("GREPTIME_TIMEOUT_MS", 30_000))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            auto_create_db: source.flag("AUTO_CREATE_DB"),
+            strip_prefixes: source.list("STRIP_PREFIXES"),
+            value_keys: source.list_or("JSON_VALUE_KEYS", &["value", "heart_rate", "hr", "bpm"]),
+            time_keys: source.list_or("JSON_TIME_KEYS", &["timestamp", "time", "ts"]),
+            value_label_keys: source.list_or("VALUE_LABEL_KEYS", &["value", "heart_rate", "hr", "bpm"]),
+            time_label_keys: source.list_or("TIME_LABEL_KEYS", &["timestamp", "time", "ts"]),
+            clock_offset_secs: clamp_clock_offset(source.or("CLOCK_OFFSET_SECS", 0)),
+            outlier_max_deviation: source.or("OUTLIER_MAX_DEVIATION", 30.0),
+            write_sink: source.var("WRITE_SINK")
+                .map(|sink| sink.trim().to_ascii_lowercase())
+                .unwrap_or_else(|_| "greptime".to_string()),
+            sink_path: source.var("SINK_PATH").unwrap_or_else(|_| "heart_rate.lp".to_string()),
+            write_method: match source.var("GREPTIME_WRITE_METHOD") {
+                Ok(value) => match value.trim().to_ascii_uppercase().as_str() {
+                    "POST" => reqwest::Method::POST,
+                    "PUT" => reqwest::Method::PUT,
+                    _ => {
+                        warn!("Ignoring invalid GREPTIME_WRITE_METHOD '{}', expected POST or PUT", value);
+                        reqwest::Method::POST
+                    }
+                },
+                Err(_) => reqwest::Method::POST,
+            },
+            write_backend: source.var("WRITE_BACKEND")
+                .map(|backend| backend.trim().to_ascii_lowercase())
+                .unwrap_or_else(|_| "influxdb".to_string()),
+            fallback_to_file: source.flag("FALLBACK_TO_FILE"),
+            route_prefix: normalize_route_prefix(&source.var("ROUTE_PREFIX").unwrap_or_default()),
+            compress_responses: source.flag("COMPRESS_RESPONSES"),
+            max_inflight_requests: Some(source.or("MAX_INFLIGHT_REQUESTS", 0)).filter(|limit| *limit > 0),
+            hr_range_mode: source.var("HR_RANGE_MODE")
+                .ok()
+                .and_then(|value| RangeMode::parse(&value))
+                .unwrap_or_default(),
+            device_id_case: source.var("DEVICE_ID_CASE")
+                .ok()
+                .and_then(|value| DeviceIdCase::parse(&value))
+                .unwrap_or_default(),
+            device_id_policy: source.var("DEVICE_ID_POLICY")
+                .ok()
+                .and_then(|value| DeviceIdPolicy::parse(&value))
+                .unwrap_or_default(),
+            min_heart_rate: source.or("MIN_HEART_RATE", 30.0),
+            max_heart_rate: source.or("MAX_HEART_RATE", 220.0),
+            measurement_per_device: source.flag("MEASUREMENT_PER_DEVICE"),
+            error_body_limit: source.or("GREPTIME_ERROR_BODY_LIMIT", 4096),
+            stream_batch_size: source.or("STREAM_BATCH_SIZE", 500).max(1),
+            strict_query_params: source.flag("STRICT_QUERY_PARAMS"),
+            silent_device_after: Some(source.or("SILENT_DEVICE_SECS", 0))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            watchdog_interval: Duration::from_secs(source.or("WATCHDOG_INTERVAL_SECS", 60).max(1)),
+            trailing_newline: source.flag("LINE_PROTOCOL_TRAILING_NEWLINE"),
+            gap_sentinels: source.list("GAP_SENTINELS")
+                .iter()
+                .filter_map(|value| value.parse().ok())
+                .collect(),
+            upload_audit: source.flag("UPLOAD_AUDIT"),
+            upload_audit_blob_dir: source.var("UPLOAD_AUDIT_BLOB_DIR").ok().filter(|dir| !dir.is_empty()),
+            local_tz: source.var("LOCAL_TZ").ok().and_then(|value| value.trim().parse().ok()),
+            timestamp_formats: timestamp_formats_from(source),
+            parse_timeout: Some(source.or("PARSE_TIMEOUT_MS", 0))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            device_line_pattern: source.var("DEVICE_LINE_PATTERN")
+                .ok()
+                .filter(|pattern| !pattern.is_empty())
+                .and_then(|pattern| match regex::Regex::new(&pattern) {
+                    Ok(re) => Some(re),
+                    Err(e) => {
+                        warn!("Ignoring invalid DEVICE_LINE_PATTERN: {}", e);
+                        None
+                    }
+                }),
+            multi_status_on_warnings: source.flag("MULTI_STATUS_ON_WARNINGS"),
+            parse_confidence: source.flag("PARSE_CONFIDENCE"),
+            value_transform: ValueTransform {
+                scale: source.or("VALUE_SCALE", 1.0),
+                offset: source.or("VALUE_OFFSET", 0.0),
+            },
+            startup_probe: source.flag("STARTUP_PROBE"),
+            allowed_content_types: source.list("ALLOWED_CONTENT_TYPES")
+                .iter()
+                .map(|media_type| media_type.to_ascii_lowercase())
+                .collect(),
+            max_hr_jump_per_sec: Some(source.or("MAX_HR_JUMP_PER_SEC", 0.0)).filter(|rate| *rate > 0.0),
+            max_age: Some(source.or("MAX_AGE_DAYS", 0))
+                .filter(|days| *days > 0)
+                .map(chrono::Duration::days),
+            hr_zones: match source.var("HR_ZONE_THRESHOLDS") {
+                Ok(value) => ZoneThresholds::parse(&value).unwrap_or_else(|e| {
+                    warn!("Ignoring invalid HR_ZONE_THRESHOLDS ({}), using defaults", e);
+                    ZoneThresholds::default()
+                }),
+                Err(_) => ZoneThresholds::default(),
+            },
+            redact_values: source.flag("REDACT_VALUES"),
+            max_warnings: source.or("MAX_WARNINGS", 100),
+            max_tags: source.or("MAX_TAGS", 10),
+            allowed_measurements: source.list("ALLOWED_MEASUREMENTS"),
+            success_message_template: source.var("SUCCESS_MESSAGE_TEMPLATE").ok().filter(|template| !template.is_empty()),
+            write_debounce: Some(source.or("WRITE_DEBOUNCE_MS", 0))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            dedup_window: Some(source.or("DEDUP_WINDOW_SECS", 0))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            rollup_at: source.var("ROLLUP_AT").ok().and_then(|value| {
+                let time = chrono::NaiveTime::parse_from_str(value.trim(), "%H:%M").ok();
+                if time.is_none() {
+                    warn!("Ignoring invalid ROLLUP_AT '{}', expected HH:MM", value);
+                }
+                time
+            }),
+            raw_retention_days: Some(source.or("RAW_RETENTION_DAYS", 0)).filter(|days| *days > 0),
+            shutdown_flush_timeout: Duration::from_millis(source.or("SHUTDOWN_FLUSH_TIMEOUT_MS", 10_000)),
         }
     }
 }
 
-#[derive(Debug)]
-struct HeartRateRecord {
-    value: f64,
-    timestamp: DateTime<Utc>,
-}
+// Largest clock correction accepted; a device clock off by more than a day is misconfigured
+const MAX_CLOCK_OFFSET_SECS: i64 = 86_400;
 
-// Fixed heart rate data parsing function
-fn parse_heart_rate_data(text: &str) -> Result<Vec<HeartRateRecord>, Box<dyn std::error::Error>> {
-    let lines: Vec<&str> = text.trim().lines()
-        .map(|line| line.trim())
-        .filter(|line| !line.is_empty())  // Filter empty lines
-        .collect();
+fn clamp_clock_offset(secs: i64) -> i64 {
+    let clamped = secs.clamp(-MAX_CLOCK_OFFSET_SECS, MAX_CLOCK_OFFSET_SECS);
+    if clamped != secs {
+        warn!("CLOCK_OFFSET_SECS {} is out of range, using {}", secs, clamped);
+    }
+    clamped
+}
 
-    let mut records = Vec::new();
-    let mut heart_rates = Vec::new();
-    let mut timestamps = Vec::new();
+// KEY=VALUE file read at startup and on POST /reload (CONFIG_FILE)
+fn config_file_path() -> Option<String> {
+    std::env::var("CONFIG_FILE").ok().filter(|path| !path.is_empty())
+}
 
-    println!("Total non-empty lines: {}", lines.len());
+// Where configuration is read from: CONFIG_FILE settings, then environment variables.
+// The file is never exported to the environment, which isn't safe to modify once threads run.
+#[derive(Debug, Default)]
+struct ConfigSource {
+    file: HashMap<String, String>,
+}
 
-    // Step 1: Collect heart rate values and timestamps separately
-    for (i, line) in lines.iter().enumerate() {
-        // Try to parse as heart rate value (number)
-        if let Ok(heart_rate) = line.parse::<f64>() {
-            // Check reasonable heart rate range (30-220 BPM)
-            if heart_rate >= 30.0 && heart_rate <= 220.0 {
-                heart_rates.push(heart_rate);
-                println!("Found heart rate: {} at line {}", heart_rate, i);
+impl ConfigSource {
+    // Read each `KEY=VALUE` line of a config file. Blank lines and `#` comments are skipped;
+    // a key removed from the file falls back to the environment on the next reload.
+    fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = std::fs::read_to_string(path)?;
+        let mut file = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
                 continue;
             }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected KEY=VALUE", i + 1))?;
+            file.insert(key.trim().to_string(), value.trim().to_string());
         }
+        Ok(Self { file })
+    }
 
-        // Try to parse as timestamp
-        if let Some(timestamp) = parse_chinese_datetime(line) {
-            timestamps.push(timestamp);
-            println!("Found timestamp: {} at line {}", timestamp, i);
-            continue;
+    // A setting from the file, else from the environment
+    fn var(&self, key: &str) -> Result<String, std::env::VarError> {
+        match self.file.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => std::env::var(key),
         }
+    }
 
-        // If neither heart rate nor timestamp, print warning
-        println!("Warning: Could not parse line {}: '{}'", i, line);
+    // Parse a setting, falling back to the default when unset or invalid
+    fn or<T: std::str::FromStr>(&self, key: &str, default: T) -> T {
+        self.var(key)
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(default)
     }
 
-    println!("Found {} heart rates and {} timestamps", heart_rates.len(), timestamps.len());
+    // Comma-separated list; blank entries are skipped
+    fn list(&self, key: &str) -> Vec<String> {
+        self.var(key)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
 
-    // Step 2: Pair heart rates and timestamps
-    let pairs_count = heart_rates.len().min(timestamps.len());
+    // Like list, falling back to `default` when the setting is unset or lists nothing
+    fn list_or(&self, key: &str, default: &[&str]) -> Vec<String> {
+        let list = self.list(key);
+        if list.is_empty() {
+            default.iter().map(|item| item.to_string()).collect()
+        } else {
+            list
+        }
+    }
 
-    if pairs_count == 0 {
-        return Err("No valid heart rate and timestamp pairs found".into());
+    // Boolean flag: "1", "true", "yes" and "on" enable it
+    fn flag(&self, key: &str) -> bool {
+        self.var(key)
+            .map(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+            .unwrap_or(false)
     }
+}
 
-    // Based on data format, there might be several pairing methods:
-    // 1. Heart rates and timestamps appear alternately in sequence
-    // 2. All heart rates first, all timestamps after
-    // 3. All timestamps first, all heart rates after
+// Ordered TIMESTAMP_FORMATS list, e.g. `rfc3339,chinese`; unknown names are skipped
+fn timestamp_formats_from(source: &ConfigSource) -> Vec<TimestampFormat> {
+    let formats: Vec<TimestampFormat> = source.list("TIMESTAMP_FORMATS")
+        .iter()
+        .filter_map(|name| {
+            let format = TimestampFormat::parse(name);
+            if format.is_none() {
+                warn!("Ignoring unknown timestamp format '{}' in TIMESTAMP_FORMATS", name);
+            }
+            format
+        })
+        .collect();
 
-    // First try to pair in sequence
-    for i in 0..pairs_count {
-        records.push(HeartRateRecord {
-            value: heart_rates[i],
-            timestamp: timestamps[i],
-        });
+    if formats.is_empty() {
+        TimestampFormat::ALL.to_vec()
+    } else {
+        formats
     }
+}
+
+// Parse a configured point in time: RFC 3339 or a plain date (midnight UTC)
+fn parse_config_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    parse_iso_datetime(value).or_else(|| {
+        chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|naive| Utc.from_utc_datetime(&naive))
+    })
+}
+
+// A batch of lines queued for the flusher, with a channel to report the write result
+#[derive(Debug)]
+struct WriteJob {
+    lines: Vec<String>,
+    // Precision the lines' timestamps are in; jobs are only batched with the same precision
+    precision: Precision,
+    // Receives how long the batch containing these lines took to write
+    respond_to: oneshot::Sender<Result<Duration, WriteError>>,
+}
 
-    // Sort by timestamp to ensure data is in chronological order
-    records.sort_by_key(|record| record.timestamp);
+// Prometheus metrics served on /metrics
+#[derive(Debug)]
+struct Metrics {
+    registry: prometheus::Registry,
+    // Latency of each write request sent to GreptimeDB (primary and mirror)
+    greptime_write_seconds: prometheus::Histogram,
+    // Lines written to the fallback file after a failed GreptimeDB write
+    fallback_lines_total: prometheus::IntCounter,
+    // Mirror writes skipped because MAX_MIRROR_WRITES were already running
+    mirror_writes_dropped_total: prometheus::IntCounter,
+}
 
-    println!("Successfully created {} heart rate records", records.len());
+impl Metrics {
+    fn new() -> Self {
+        let registry = prometheus::Registry::new();
+        let greptime_write_seconds = prometheus::Histogram::with_opts(prometheus::HistogramOpts::new(
+            "greptime_write_seconds",
+            "Latency of GreptimeDB write requests",
+        ))
+        .unwrap();
+        registry.register(Box::new(greptime_write_seconds.clone())).unwrap();
+        let fallback_lines_total = prometheus::IntCounter::new(
+            "fallback_lines_total",
+            "Lines written to the fallback file after a failed GreptimeDB write",
+        )
+        .unwrap();
+        registry.register(Box::new(fallback_lines_total.clone())).unwrap();
+        let mirror_writes_dropped_total = prometheus::IntCounter::new(
+            "mirror_writes_dropped_total",
+            "Mirror writes skipped because MAX_MIRROR_WRITES were already running",
+        )
+        .unwrap();
+        registry.register(Box::new(mirror_writes_dropped_total.clone())).unwrap();
 
-    // Print first few records for debugging
-    for (i, record) in records.iter().take(5).enumerate() {
-        println!("Record {}: {} BPM at {}", i + 1, record.value, record.timestamp);
+        Self { registry, greptime_write_seconds, fallback_lines_total, mirror_writes_dropped_total }
     }
 
-    Ok(records)
+    fn render(&self) -> String {
+        use prometheus::Encoder;
+
+        let mut buffer = Vec::new();
+        if let Err(e) = prometheus::TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
 }
 
-// Parse Chinese datetime format: 2025年6月2日 21:28
-fn parse_chinese_datetime(datetime_str: &str) -> Option<DateTime<Utc>> {
-    // Use regex to parse Chinese date format
-    let re = regex::Regex::new(r"(\d{4})年(\d{1,2})月(\d{1,2})日\s+(\d{1,2}):(\d{2})").ok()?;
+// Record timestamp -> when it was written
+type WrittenTimestamps = HashMap<DateTime<Utc>, Instant>;
 
-    if let Some(caps) = re.captures(datetime_str) {
-        let year: i32 = caps[1].parse().ok()?;
-        let month: u32 = caps[2].parse().ok()?;
-        let day: u32 = caps[3].parse().ok()?;
-        let hour: u32 = caps[4].parse().ok()?;
-        let minute: u32 = caps[5].parse().ok()?;
+#[derive(Debug, Clone)]
+struct AppState {
+    // Swapped wholesale by POST /reload; read it through config()
+    config: Arc<RwLock<Arc<Config>>>,
+    // CONFIG_FILE, re-read by POST /reload
+    config_file: Option<String>,
+    http_client: Client,
+    write_tx: mpsc::Sender<WriteJob>,
+    // device_id -> device_name, loaded from DEVICE_MAP_PATH
+    device_names: Arc<RwLock<HashMap<String, String>>>,
+    // Process start, for the uptime reported by /health
+    started_at: Instant,
+    // Where flushed batches go (GreptimeDB or a local file)
+    writer: Arc<dyn Writer>,
+    // One permit per running mirror write, sized by MAX_MIRROR_WRITES at startup
+    mirror_permits: Arc<tokio::sync::Semaphore>,
+    // Running mirror writes, awaited at shutdown
+    mirror_writes: Arc<std::sync::Mutex<tokio::task::JoinSet<()>>>,
+    // device_id -> time of the last successful write, watched by run_watchdog
+    last_seen: Arc<RwLock<HashMap<String, Instant>>>,
+    // device_id -> recently written record timestamps, for DEDUP_WINDOW_SECS
+    recent_writes: Arc<RwLock<HashMap<String, WrittenTimestamps>>>,
+    // device_id -> when its last write request was accepted, for WRITE_DEBOUNCE_MS; held by a
+    // DebounceSlot until the request has written something
+    write_requests: Arc<RwLock<HashMap<String, Instant>>>,
+    metrics: Arc<Metrics>,
+    // Set once a write (or the startup probe) has succeeded; reported by /health/ready
+    ready: Arc<AtomicBool>,
+    // Error of the latest write (or startup probe) and when it happened; cleared by a success
+    last_write_error: Arc<RwLock<Option<WriteFailure>>>,
+}
 
-        // Assume timezone is UTC+8 (China timezone)
-        let naive = NaiveDateTime::new(
-            chrono::NaiveDate::from_ymd_opt(year, month, day)?,
-            chrono::NaiveTime::from_hms_opt(hour, minute, 0)?
-        );
+// A device's debounce window reserved by check_debounce. Dropping it before commit frees the
+// window again, so a request that wrote nothing doesn't block the corrected resend
+struct DebounceSlot {
+    write_requests: Arc<RwLock<HashMap<String, Instant>>>,
+    device_id: String,
+    reserved_at: Option<Instant>,
+}
 
-        // Convert to UTC (subtract 8 hours)
-        let utc_time = naive - chrono::Duration::hours(8);
-        return Some(DateTime::from_naive_utc_and_offset(utc_time, Utc));
+impl DebounceSlot {
+    // Keep the window: the request wrote something
+    fn commit(mut self) {
+        self.reserved_at = None;
     }
+}
 
-    None
+impl Drop for DebounceSlot {
+    fn drop(&mut self) {
+        let Some(reserved_at) = self.reserved_at else {
+            return;
+        };
+        let mut write_requests = self.write_requests.write().unwrap();
+        // Only our own reservation; the window may have expired and been taken by another request
+        if write_requests.get(&self.device_id) == Some(&reserved_at) {
+            write_requests.remove(&self.device_id);
+        }
+    }
 }
 
-// Convert to InfluxDB Line Protocol format
-fn to_influxdb_line(record: &HeartRateRecord, device_id: &str) -> String {
-    let timestamp_ms = record.timestamp.timestamp_millis();
+impl AppState {
+    fn new(config: Config, write_tx: mpsc::Sender<WriteJob>) -> Self {
+        Self {
+            http_client: build_http_client(&config),
+            writer: build_writer(&config),
+            mirror_permits: Arc::new(tokio::sync::Semaphore::new(config.max_mirror_writes)),
+            mirror_writes: Arc::new(std::sync::Mutex::new(tokio::task::JoinSet::new())),
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            config_file: config_file_path(),
+            write_tx,
+            device_names: Arc::new(RwLock::new(HashMap::new())),
+            started_at: Instant::now(),
+            last_seen: Arc::new(RwLock::new(HashMap::new())),
+            recent_writes: Arc::new(RwLock::new(HashMap::new())),
+            write_requests: Arc::new(RwLock::new(HashMap::new())),
+            metrics: Arc::new(Metrics::new()),
+            ready: Arc::new(AtomicBool::new(false)),
+            last_write_error: Arc::new(RwLock::new(None)),
+        }
+    }
 
-    format!(
-        "heart_rate,device_id={} value={} {}",
-        device_id.replace(" ", "\\ ").replace(",", "\\,"),
-        record.value,
-        timestamp_ms
-    )
+    // Snapshot of the current configuration
+    fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    // Wait for the mirror writes still running, e.g. at shutdown
+    async fn drain_mirror_writes(&self) {
+        let mut mirror_writes = std::mem::take(&mut *self.mirror_writes.lock().unwrap());
+        while mirror_writes.join_next().await.is_some() {}
+    }
+
+    // Request to GreptimeDB, with basic auth when credentials are configured
+    fn greptime_request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let config = self.config();
+        let request = self.http_client.request(method, url);
+        match &config.greptime_username {
+            Some(username) => request.basic_auth(username, config.greptime_password.as_ref()),
+            None => request,
+        }
+    }
+
+    // Remember the outcome of a write for /health/ready
+    fn record_write_result(&self, error: Option<String>) {
+        *self.last_write_error.write().unwrap() = error.map(|message| WriteFailure { message, at: Utc::now() });
+    }
+
+    // (Re)load the device name mapping; keeps the previous mapping on failure
+    fn reload_device_names(&self) {
+        let config = self.config();
+        let path = match &config.device_map_path {
+            Some(path) => path,
+            None => return,
+        };
+
+        match load_device_map(path) {
+            Ok(map) => {
+                info!("Loaded {} device names from {}", map.len(), path);
+                *self.device_names.write().unwrap() = map;
+            }
+            Err(e) => error!("Failed to load device map {}: {}", path, e),
+        }
+    }
+
+    fn device_name(&self, device_id: &str) -> Option<String> {
+        self.device_names.read().unwrap().get(device_id).cloned()
+    }
+
+    fn mark_seen(&self, device_id: &str) {
+        self.last_seen.write().unwrap().insert(device_id.to_string(), Instant::now());
+    }
+
+    // Check a write request for the device against the debounce window and reserve the window
+    // under the same lock, so two requests racing in can't both pass; reports how long until
+    // the window held by an earlier request ends
+    fn check_debounce(&self, device_id: &str) -> Result<DebounceSlot, Duration> {
+        let mut slot = DebounceSlot {
+            write_requests: self.write_requests.clone(),
+            device_id: device_id.to_string(),
+            reserved_at: None,
+        };
+        let Some(window) = self.config().write_debounce else {
+            return Ok(slot);
+        };
+
+        let now = Instant::now();
+        let mut write_requests = self.write_requests.write().unwrap();
+        write_requests.retain(|_, accepted_at| now.saturating_duration_since(*accepted_at) < window);
+        if let Some(accepted_at) = write_requests.get(device_id) {
+            return Err(window.saturating_sub(now.saturating_duration_since(*accepted_at)));
+        }
+        write_requests.insert(device_id.to_string(), now);
+        slot.reserved_at = Some(now);
+        Ok(slot)
+    }
+
+    // Drop records whose timestamps were written for this device within the dedup window
+    fn drop_recently_written(&self, device_id: &str, records: Vec<HeartRateRecord>) -> (Vec<HeartRateRecord>, usize) {
+        let Some(window) = self.config().dedup_window else {
+            return (records, 0);
+        };
+        let recent_writes = self.recent_writes.read().unwrap();
+        let Some(written) = recent_writes.get(device_id) else {
+            return (records, 0);
+        };
+
+        let now = Instant::now();
+        let before = records.len();
+        let records: Vec<HeartRateRecord> = records
+            .into_iter()
+            .filter(|record| {
+                written
+                    .get(&record.timestamp)
+                    .is_none_or(|written_at| now.saturating_duration_since(*written_at) > window)
+            })
+            .collect();
+        let skipped = before - records.len();
+        (records, skipped)
+    }
+
+    // Remember written timestamps for drop_recently_written, forgetting ones past the window
+    fn remember_written(&self, device_id: &str, timestamps: impl IntoIterator<Item = DateTime<Utc>>) {
+        let Some(window) = self.config().dedup_window else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut recent_writes = self.recent_writes.write().unwrap();
+        recent_writes.retain(|_, written| {
+            written.retain(|_, written_at| now.saturating_duration_since(*written_at) <= window);
+            !written.is_empty()
+        });
+        recent_writes
+            .entry(device_id.to_string())
+            .or_default()
+            .extend(timestamps.into_iter().map(|timestamp| (timestamp, now)));
+    }
+
+    // Devices not seen within the watchdog threshold, with how long they have been silent
+    fn silent_devices(&self, now: Instant) -> Vec<(String, Duration)> {
+        match self.config().silent_device_after {
+            Some(threshold) => silent_devices(&self.last_seen.read().unwrap(), now, threshold),
+            None => Vec::new(),
+        }
+    }
 }
 
-// Send data to GreptimeDB
-async fn send_to_greptime(
-    app_state: &AppState,
-    lines: Vec<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let body = lines.join("\n");
+fn silent_devices(last_seen: &HashMap<String, Instant>, now: Instant, threshold: Duration) -> Vec<(String, Duration)> {
+    let mut silent: Vec<(String, Duration)> = last_seen
+        .iter()
+        .map(|(device_id, seen)| (device_id.clone(), now.saturating_duration_since(*seen)))
+        .filter(|(_, silent_for)| *silent_for > threshold)
+        .collect();
+    silent.sort();
+    silent
+}
 
-    let url = format!(
-        "{}/v1/influxdb/api/v2/write?db={}&precision=ms",
-        app_state.greptime_url,
-        app_state.greptime_db
-    );
+// Periodically warn about devices that stopped reporting; each device is reported
+// once per silence and again only after it has come back
+async fn run_watchdog(app_state: AppState) {
+    let mut ticker = tokio::time::interval(app_state.config().watchdog_interval);
+    let mut reported: HashSet<String> = HashSet::new();
 
-    println!("Sending to GreptimeDB: {}", url);
-    println!("Sending {} lines of data", lines.len());
+    loop {
+        ticker.tick().await;
 
-    let response = app_state
-        .http_client
-        .post(&url)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(body)
-        .send()
-        .await?;
+        let silent = app_state.silent_devices(Instant::now());
+        reported.retain(|device_id| silent.iter().any(|(silent_id, _)| silent_id == device_id));
 
-    if !response.status().is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(format!("GreptimeDB error: {}", error_text).into());
+        for (device_id, silent_for) in silent {
+            if reported.insert(device_id.clone()) {
+                warn!("No data received from device {} for {}s", device_id, silent_for.as_secs());
+            }
+        }
     }
+}
 
-    println!("Successfully sent to GreptimeDB");
-    Ok(())
+// Build the GreptimeDB HTTP client with the configured HTTP/2, keep-alive and timeout settings
+fn build_http_client(config: &Config) -> Client {
+    let mut builder = Client::builder()
+        .tcp_keepalive(config.greptime_keepalive)
+        .pool_idle_timeout(config.greptime_pool_idle_timeout);
+
+    if let Some(timeout) = config.greptime_timeout {
+        builder = builder.timeout(timeout);
+    }
+
+    if config.greptime_http2 {
+        // GreptimeDB speaks h2c, so skip the HTTP/1.1 upgrade dance
+        builder = builder
+            .http2_prior_knowledge()
+            .http2_keep_alive_interval(config.greptime_keepalive)
+            .http2_keep_alive_while_idle(true);
+    }
+
+    builder.build().unwrap_or_else(|e| {
+        warn!("Failed to build HTTP client ({}), using defaults", e);
+        Client::new()
+    })
 }
 
-// Main processing function
-async fn process_heart_rate_text(
-    axum::extract::State(app_state): axum::extract::State<AppState>,
-    Query(params): Query<QueryParams>,
-    body: Bytes,
-) -> Result<ResponseJson<ApiResponse>, (StatusCode, String)> {
+// Read a JSON object of the form {"<device_id>": "<device name>", ...}
+fn load_device_map(path: &str) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
 
-    // Convert bytes to string
-    let text = match String::from_utf8(body.to_vec()) {
-        Ok(text) => text,
+// Reload the device map whenever the process receives SIGHUP
+#[cfg(unix)]
+async fn reload_device_names_on_sighup(app_state: AppState) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
         Err(e) => {
-            return Err((StatusCode::BAD_REQUEST, format!("Invalid UTF-8: {}", e)));
+            error!("Failed to install SIGHUP handler: {}", e);
+            return;
         }
     };
 
-    let device_id = params.device_id.unwrap_or_else(|| "apple-watch".to_string());
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading device map");
+        app_state.reload_device_names();
+    }
+}
+
+#[derive(Debug)]
+struct HeartRateRecord {
+    value: f64,
+    timestamp: DateTime<Utc>,
+    // UTC offset the source timestamp was resolved in, e.g. 480 for UTC+8
+    offset_minutes: i32,
+    // Change from the previous record, written as a `delta` field when set
+    delta: Option<f64>,
+    // Source range (min, max) for values given as `68-74`, written as fields in min/max mode
+    range: Option<(f64, f64)>,
+    // Sensor confidence given next to the reading, written as a `confidence` field when set
+    confidence: Option<f64>,
+    // Line of the body the value came from, written as a `source_line` field when set
+    source_line: Option<usize>,
+}
 
-    println!("=== Received Heart Rate Data ===");
-    println!("Device ID: {}", device_id);
-    println!("Data length: {} characters", text.len());
-    println!("First 500 characters of raw data:\n{}",
-             if text.len() > 500 { &text[..500] } else { &text });
+// How values given as ranges (`68-74`) are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum RangeMode {
+    // Ranges are not recognized
+    #[default]
+    Off,
+    // Use the midpoint as the value
+    Midpoint,
+    // Use the midpoint as the value and also write `min` and `max` fields
+    MinMax,
+}
 
-    // Parse heart rate data
-    let records = match parse_heart_rate_data(&text) {
-        Ok(records) => records,
-        Err(e) => {
-            eprintln!("Failed to parse heart rate data: {}", e);
-            return Err((StatusCode::BAD_REQUEST, format!("Parse error: {}", e)));
+impl RangeMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" | "" => Some(RangeMode::Off),
+            "midpoint" => Some(RangeMode::Midpoint),
+            "minmax" => Some(RangeMode::MinMax),
+            _ => None,
         }
-    };
+    }
+}
 
-    println!("Parsed {} heart rate records", records.len());
+// Case normalization of device ids, so `Watch1` and `watch1` end up as one device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum DeviceIdCase {
+    // Keep device ids as sent
+    #[default]
+    None,
+    Lower,
+    Upper,
+}
 
-    if records.is_empty() {
-        return Ok(ResponseJson(ApiResponse {
-            success: false,
-            message: "No valid heart rate records found".to_string(),
-            processed_count: 0,
-        }));
+impl DeviceIdCase {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "none" | "" => Some(DeviceIdCase::None),
+            "lower" => Some(DeviceIdCase::Lower),
+            "upper" => Some(DeviceIdCase::Upper),
+            _ => None,
+        }
     }
 
-    // Convert to InfluxDB Line Protocol
-    let lines: Vec<String> = records
-        .iter()
-        .map(|record| to_influxdb_line(record, &device_id))
-        .collect();
+    fn apply(self, device_id: &str) -> String {
+        match self {
+            DeviceIdCase::None => device_id.to_string(),
+            DeviceIdCase::Lower => device_id.to_lowercase(),
+            DeviceIdCase::Upper => device_id.to_uppercase(),
+        }
+    }
+}
+
+// Handling of device ids containing characters such as `/` or `.`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum DeviceIdPolicy {
+    // Use device ids as sent
+    #[default]
+    Allow,
+    // Answer 400
+    Reject,
+    // Replace each unsafe character with `_`
+    Sanitize,
+}
+
+impl DeviceIdPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "allow" | "" => Some(DeviceIdPolicy::Allow),
+            "reject" => Some(DeviceIdPolicy::Reject),
+            "sanitize" => Some(DeviceIdPolicy::Sanitize),
+            _ => None,
+        }
+    }
+}
 
-    println!("Generated {} InfluxDB lines", lines.len());
+fn is_safe_device_id_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-')
+}
 
-    // Only print first few lines for debugging
-    println!("First few InfluxDB lines:");
-    for (i, line) in lines.iter().take(3).enumerate() {
-        println!("  {}: {}", i + 1, line);
+// Apply DEVICE_ID_CASE and DEVICE_ID_POLICY to a device id before it is used
+fn normalize_device_id(config: &Config, device_id: &str) -> Result<String, (StatusCode, String)> {
+    let device_id = config.device_id_case.apply(device_id);
+    if device_id.chars().all(is_safe_device_id_char) {
+        return Ok(device_id);
     }
-    if lines.len() > 3 {
-        println!("  ... and {} more lines", lines.len() - 3);
+    match config.device_id_policy {
+        DeviceIdPolicy::Allow => Ok(device_id),
+        DeviceIdPolicy::Reject => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid device_id '{}': only letters, digits, '_' and '-' are allowed", device_id),
+        )),
+        DeviceIdPolicy::Sanitize => {
+            let sanitized: String = device_id
+                .chars()
+                .map(|c| if is_safe_device_id_char(c) { c } else { '_' })
+                .collect();
+            info!("Sanitized device_id '{}' to '{}'", device_id, sanitized);
+            Ok(sanitized)
+        }
+    }
+}
+
+// How parsed values are matched with parsed timestamps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum PairingStrategy {
+    // The n-th value goes with the n-th timestamp, whatever the layout
+    #[default]
+    Sequential,
+    // Each value pairs with the line right after it (or before it, when the body starts
+    // with a timestamp); entries without an adjacent partner are skipped
+    Alternating,
+    // A block of values followed by a block of timestamps; values after the first timestamp are ignored
+    ValuesFirst,
+    // A block of timestamps followed by a block of values; timestamps after the first value are ignored
+    TimestampsFirst,
+    // When counts differ, spread the shorter list over the longer one by relative position
+    // instead of cutting off the tail, e.g. 9 timestamps over 10 values drop one value mid-way
+    Proportional,
+}
+
+impl PairingStrategy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PairingStrategy::Sequential => "sequential",
+            PairingStrategy::Alternating => "alternating",
+            PairingStrategy::ValuesFirst => "values_first",
+            PairingStrategy::TimestampsFirst => "timestamps_first",
+            PairingStrategy::Proportional => "proportional",
+        }
     }
 
-    // Send to GreptimeDB
-    if let Err(e) = send_to_greptime(&app_state, lines).await {
-        eprintln!("Failed to send to GreptimeDB: {}", e);
-        return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("GreptimeDB error: {}", e)));
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "sequential" => Some(PairingStrategy::Sequential),
+            "alternating" => Some(PairingStrategy::Alternating),
+            "values_first" => Some(PairingStrategy::ValuesFirst),
+            "timestamps_first" => Some(PairingStrategy::TimestampsFirst),
+            "proportional" => Some(PairingStrategy::Proportional),
+            _ => None,
+        }
     }
+}
 
-    println!("=== Processing Complete ===");
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    Value,
+    Timestamp,
+}
 
-    Ok(ResponseJson(ApiResponse {
-        success: true,
-        message: format!("Successfully processed {} heart rate records", records.len()),
-        processed_count: records.len(),
-    }))
+// Pair up entries, given the kinds of the value/timestamp lines in input order.
+// Returns (value index, timestamp index) pairs into the separately collected lists.
+fn pair_entries(order: &[EntryKind], strategy: PairingStrategy) -> Vec<(usize, usize)> {
+    let value_count = order.iter().filter(|kind| **kind == EntryKind::Value).count();
+    let timestamp_count = order.len() - value_count;
+    let leading = |kind: EntryKind| order.iter().take_while(|entry| **entry == kind).count();
+
+    let count = match strategy {
+        PairingStrategy::Sequential => value_count.min(timestamp_count),
+        PairingStrategy::ValuesFirst => leading(EntryKind::Value).min(timestamp_count),
+        PairingStrategy::TimestampsFirst => leading(EntryKind::Timestamp).min(value_count),
+        PairingStrategy::Proportional => {
+            if value_count == timestamp_count {
+                value_count
+            } else {
+                let shorter = value_count.min(timestamp_count);
+                let longer = value_count.max(timestamp_count);
+                let positions = proportional_positions(shorter, longer);
+                return if value_count < timestamp_count {
+                    positions.into_iter().enumerate().collect()
+                } else {
+                    positions.into_iter().enumerate().map(|(t, v)| (v, t)).collect()
+                };
+            }
+        }
+        PairingStrategy::Alternating => {
+            let lead = match order.first() {
+                Some(lead) => *lead,
+                None => return Vec::new(),
+            };
+            let mut pairs = Vec::new();
+            let (mut value, mut timestamp) = (0, 0);
+            let mut i = 0;
+            while i < order.len() {
+                if order[i] == lead && order.get(i + 1).is_some_and(|next| *next != lead) {
+                    pairs.push((value, timestamp));
+                    value += 1;
+                    timestamp += 1;
+                    i += 2;
+                } else {
+                    match order[i] {
+                        EntryKind::Value => value += 1,
+                        EntryKind::Timestamp => timestamp += 1,
+                    }
+                    i += 1;
+                }
+            }
+            return pairs;
+        }
+    };
+
+    (0..count).map(|i| (i, i)).collect()
 }
 
-async fn health_check() -> &'static str {
-    "OK"
+// For each of `shorter` items, the index of the item at the same relative position among
+// `longer` ones; first maps to first and last to last
+fn proportional_positions(shorter: usize, longer: usize) -> Vec<usize> {
+    match shorter {
+        0 => Vec::new(),
+        1 => vec![0],
+        _ => (0..shorter)
+            .map(|i| ((i * (longer - 1)) as f64 / (shorter - 1) as f64).round() as usize)
+            .collect(),
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    // Read configuration from environment variables
-    let greptime_url = std::env::var("GREPTIME_URL")
-        .unwrap_or_else(|_| "http://127.0.0.1".to_string());
-    let greptime_db = std::env::var("GREPTIME_DB")
-        .unwrap_or_else(|_| "heartbeat_test".to_string());
-    let port = std::env::var("PORT")
-        .unwrap_or_else(|_| "3000".to_string())
-        .parse::<u16>()
-        .unwrap_or(3000);
-
-    let app_state = AppState::new(greptime_url.clone(), greptime_db.clone());
-
-    println!("Starting heart rate proxy server...");
-    println!("GreptimeDB URL: {}", greptime_url);
-    println!("Database: {}", greptime_db);
-    println!("Server port: {}", port);
-
-    let app = Router::new()
-        .route("/heart-rate", post(process_heart_rate_text))
-        .route("/health", axum::routing::get(health_check))
-        .layer(
-            ServiceBuilder::new()
-                .layer(CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any))
-        )
-        .with_state(app_state);
+// `value * scale + offset`, converting a sensor's unit to BPM
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ValueTransform {
+    scale: f64,
+    offset: f64,
+}
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
-        .await
-        .unwrap();
+impl Default for ValueTransform {
+    fn default() -> Self {
+        Self { scale: 1.0, offset: 0.0 }
+    }
+}
+
+impl ValueTransform {
+    fn apply(&self, value: f64) -> f64 {
+        value * self.scale + self.offset
+    }
+}
 
-    println!("Server running on http://0.0.0.0:{}", port);
+// A value line accepted by the parser
+#[derive(Debug, Clone, Copy)]
+struct ParsedValue {
+    value: f64,
+    range: Option<(f64, f64)>,
+    confidence: Option<f64>,
+    // 1-based line number in the body
+    line_number: usize,
+}
 
-    axum::serve(listener, app).await.unwrap();
-}
\ No newline at end of file
+// Split a trailing confidence token off a value line:
+// `72 confidence=0.9`, `72 conf:0.9`, `68-74 (confidence: 0.9)`
+fn split_confidence(line: &str) -> (&str, Option<f64>) {
+    static PATTERN: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| {
+        regex::Regex::new(r"(?i)^(.*?)\s+[(\[]?conf(?:idence)?\s*[:=]\s*([0-9]*\.?[0-9]+)[)\]]?$").unwrap()
+    });
+
+    match pattern.captures(line) {
+        Some(caps) => match caps[2].parse::<f64>() {
+            Ok(confidence) => (caps.get(1).map_or(line, |value| value.as_str()), Some(confidence)),
+            Err(_) => (line, None),
+        },
+        None => (line, None),
+    }
+}
+
+// Parse a number. Accepted forms are an optional sign and digits with at most one decimal
+// point, at least one digit on either side of it: `72`, `072`, `72.`, `.5`, `72.5`, `+72`.
+// Exponents, `inf` and `nan` are not numbers here. With `decimal_comma` the separator is a
+// comma instead (`72,5`), while anything else with commas, like `72,5,3` or `1,234.5`, is
+// left unparsed.
+fn parse_number(text: &str, decimal_comma: bool) -> Option<f64> {
+    let separator = if decimal_comma && text.contains(',') { ',' } else { '.' };
+    let unsigned = text.strip_prefix(['-', '+']).unwrap_or(text);
+    let (whole, fraction) = unsigned.split_once(separator).unwrap_or((unsigned, ""));
+
+    let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !is_digits(whole) || !is_digits(fraction) {
+        return None;
+    }
+
+    let sign = if text.starts_with('-') { "-" } else { "" };
+    let whole = if whole.is_empty() { "0" } else { whole };
+    let fraction = if fraction.is_empty() { "0" } else { fraction };
+    format!("{}{}.{}", sign, whole, fraction).parse().ok()
+}
+
+// Parse `N-M` (also `N~M`, `N–M`) into (min, max)
+fn parse_value_range(line: &str, decimal_comma: bool) -> Option<(f64, f64)> {
+    let (low, high) = line.split_once(['-', '~', '–'])?;
+    let low = parse_number(low.trim(), decimal_comma)?;
+    let high = parse_number(high.trim(), decimal_comma)?;
+    (low.is_finite() && high.is_finite() && low <= high).then_some((low, high))
+}
+
+// Per-request options controlling the measurement and which extra fields go into each line
+#[derive(Debug, Default, Clone)]
+struct LineOptions {
+    // Measurement (table) name the lines are written to
+    measurement: String,
+    // Write the source timezone offset as an `offset_minutes` field
+    store_offset: bool,
+    // Tag each line with its heart-rate zone
+    with_zone: bool,
+    // Units of the line timestamps
+    precision: Precision,
+    // Server receive time, written as an `ingested_at` field (epoch milliseconds) when set
+    ingested_at: Option<DateTime<Utc>>,
+}
+
+// Lower bounds (BPM) of the fat_burn, cardio and peak zones; anything below is rest
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ZoneThresholds {
+    fat_burn: f64,
+    cardio: f64,
+    peak: f64,
+}
+
+impl Default for ZoneThresholds {
+    fn default() -> Self {
+        Self { fat_burn: 100.0, cardio: 140.0, peak: 170.0 }
+    }
+}
+
+impl ZoneThresholds {
+    // `fat_burn,cardio,peak`, strictly ascending, e.g. `100,140,170`
+    fn parse(value: &str) -> Result<Self, String> {
+        let bounds: Vec<f64> = value
+            .split(',')
+            .map(|bound| bound.trim().parse::<f64>().map_err(|_| format!("'{}' is not a number", bound.trim())))
+            .collect::<Result<_, _>>()?;
+        match bounds[..] {
+            [fat_burn, cardio, peak] if fat_burn < cardio && cardio < peak => Ok(Self { fat_burn, cardio, peak }),
+            [_, _, _] => Err("thresholds must be strictly ascending".to_string()),
+            _ => Err(format!("expected 3 thresholds, got {}", bounds.len())),
+        }
+    }
+
+    // Zone name for a reading; each threshold is the inclusive start of its zone
+    fn zone(&self, value: f64) -> &'static str {
+        if value >= self.peak {
+            "peak"
+        } else if value >= self.cardio {
+            "cardio"
+        } else if value >= self.fat_burn {
+            "fat_burn"
+        } else {
+            "rest"
+        }
+    }
+}
+
+// Check the API token from the `Authorization: Bearer` header or, if allowed, the `token` query param
+fn authorize(
+    config: &Config,
+    headers: &HeaderMap,
+    query_token: Option<&str>,
+) -> Result<(), (StatusCode, String)> {
+    let expected = match &config.api_token {
+        Some(token) => token,
+        None => return Ok(()),
+    };
+
+    let header_token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    if header_token == Some(expected.as_str()) {
+        return Ok(());
+    }
+
+    if config.allow_query_token && query_token == Some(expected.as_str()) {
+        return Ok(());
+    }
+
+    Err((StatusCode::UNAUTHORIZED, "Missing or invalid API token".to_string()))
+}
+
+// Like authorize, but for destructive routes, which are closed entirely rather than open
+// when no API_TOKEN is configured
+fn authorize_destructive(
+    config: &Config,
+    headers: &HeaderMap,
+    query_token: Option<&str>,
+) -> Result<(), (StatusCode, String)> {
+    if config.api_token.is_none() {
+        return Err((StatusCode::FORBIDDEN, "This endpoint requires API_TOKEN to be configured".to_string()));
+    }
+    authorize(config, headers, query_token)
+}
+
+// Options controlling how input text is parsed
+#[derive(Debug, Default, Clone)]
+struct ParseOptions {
+    // Prefixes removed from the start of each line before parsing (e.g. "HR:", "TS:")
+    strip_prefixes: Vec<String>,
+    // Constant correction added to every parsed timestamp for a drifting device clock
+    clock_offset: chrono::Duration,
+    // Whether `68-74` style ranges are accepted as values
+    range_mode: RangeMode,
+    // "No reading" markers; they hold their timestamp's slot but produce no record
+    gap_sentinels: Vec<f64>,
+    // Timezone naive local timestamps are read in
+    local_tz: Option<chrono_tz::Tz>,
+    // Timestamp formats tried on each line, first match wins
+    timestamp_formats: Vec<TimestampFormat>,
+    // Recognizes a line carrying the device_id
+    device_line_pattern: Option<regex::Regex>,
+    // How values are matched with timestamps
+    strategy: PairingStrategy,
+    // Accept a confidence token after each value (see split_confidence)
+    parse_confidence: bool,
+    // Keep records in source order rather than sorting them by timestamp
+    preserve_order: bool,
+    // Applied to every value (and range bound) before validation
+    value_transform: ValueTransform,
+    // Timestamps generated when the body has values but none at all (`synthesize_ts=true`)
+    synthesize_timestamps: Option<SynthesizedTimestamps>,
+    // Accept `72,5` style values (`decimal_comma=true`)
+    decimal_comma: bool,
+    // Keep each value's line number on its record (`with_source_line=true`)
+    with_source_line: bool,
+    // Plausible heart rate range; numbers outside it are not taken as values
+    min_heart_rate: f64,
+    max_heart_rate: f64,
+    // The body is a JSON array of objects rather than a text export (Content-Type: application/json)
+    json_input: bool,
+    value_keys: Vec<String>,
+    time_keys: Vec<String>,
+    value_label_keys: Vec<String>,
+    time_label_keys: Vec<String>,
+}
+
+// Evenly spaced timestamps for timestamp-less input, the last value at `end`
+#[derive(Debug, Clone, Copy)]
+struct SynthesizedTimestamps {
+    interval: chrono::Duration,
+    end: DateTime<Utc>,
+}
+
+impl SynthesizedTimestamps {
+    // None when the earliest timestamp would fall outside the representable range
+    fn timestamps(&self, count: usize) -> Option<Vec<DateTime<FixedOffset>>> {
+        (0..count)
+            .map(|i| {
+                let back = self.interval.checked_mul(i32::try_from(count - 1 - i).ok()?)?;
+                self.end.checked_sub_signed(back).map(|time| time.fixed_offset())
+            })
+            .collect()
+    }
+}
+
+impl ParseOptions {
+    fn from_config(config: &Config) -> Self {
+        Self {
+            strip_prefixes: config.strip_prefixes.clone(),
+            clock_offset: chrono::Duration::seconds(config.clock_offset_secs),
+            range_mode: config.hr_range_mode,
+            gap_sentinels: config.gap_sentinels.clone(),
+            local_tz: config.local_tz,
+            timestamp_formats: config.timestamp_formats.clone(),
+            device_line_pattern: config.device_line_pattern.clone(),
+            strategy: PairingStrategy::default(),
+            parse_confidence: config.parse_confidence,
+            preserve_order: false,
+            value_transform: config.value_transform,
+            synthesize_timestamps: None,
+            decimal_comma: false,
+            with_source_line: false,
+            min_heart_rate: config.min_heart_rate,
+            max_heart_rate: config.max_heart_rate,
+            json_input: false,
+            value_keys: config.value_keys.clone(),
+            time_keys: config.time_keys.clone(),
+            value_label_keys: config.value_label_keys.clone(),
+            time_label_keys: config.time_label_keys.clone(),
+        }
+    }
+}
+
+// Remove the first matching noise prefix from a line
+fn strip_line_prefix<'a>(line: &'a str, prefixes: &[String]) -> &'a str {
+    prefixes
+        .iter()
+        .find_map(|prefix| line.strip_prefix(prefix.as_str()))
+        .map(str::trim)
+        .unwrap_or(line)
+}
+
+// Drop a known label from `bpm=72` or `time=2025-06-02T21:28:00Z` style lines, returning what
+// the label says the rest is; labels come from VALUE_LABEL_KEYS / TIME_LABEL_KEYS, matched
+// case-insensitively
+fn strip_line_label<'a>(line: &'a str, options: &ParseOptions) -> (Option<EntryKind>, &'a str) {
+    let Some((key, rest)) = line.split_once('=') else {
+        return (None, line);
+    };
+    let key = key.trim();
+    let is_label = |labels: &[String]| labels.iter().any(|label| label.eq_ignore_ascii_case(key));
+    if is_label(&options.value_label_keys) {
+        (Some(EntryKind::Value), rest.trim())
+    } else if is_label(&options.time_label_keys) {
+        (Some(EntryKind::Timestamp), rest.trim())
+    } else {
+        (None, line)
+    }
+}
+
+// Parser output: the records plus how many values were gap sentinels
+#[derive(Debug, Default)]
+struct ParsedData {
+    records: Vec<HeartRateRecord>,
+    gap_count: usize,
+    // Local times that fell in a DST fall-back overlap or spring-forward gap
+    ambiguous_count: usize,
+    nonexistent_count: usize,
+    // Device named by a DEVICE_LINE_PATTERN line, first match wins
+    device_id: Option<String>,
+    // How the input lines were classified, for /diagnose
+    line_stats: LineStats,
+    // Layout of values and timestamps in the input, see detect_layout
+    layout: &'static str,
+}
+
+// Counts of each kind of input line
+#[derive(Debug, Default, Serialize)]
+struct LineStats {
+    total: usize,
+    values: usize,
+    ranges: usize,
+    gaps: usize,
+    chinese_timestamps: usize,
+    rfc3339_timestamps: usize,
+    iso_local_timestamps: usize,
+    // Lines carrying both a timestamp and a value, e.g. `2025年6月2日 21:28 72`
+    combined_lines: usize,
+    device_lines: usize,
+    unparsed: usize,
+    // The first few unparsed lines
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unparsed_samples: Vec<String>,
+}
+
+impl LineStats {
+    fn count_timestamp(&mut self, format: TimestampFormat) {
+        match format {
+            TimestampFormat::Chinese => self.chinese_timestamps += 1,
+            TimestampFormat::Rfc3339 => self.rfc3339_timestamps += 1,
+            TimestampFormat::IsoLocal => self.iso_local_timestamps += 1,
+        }
+    }
+}
+
+const UNPARSED_SAMPLE_LIMIT: usize = 5;
+
+// Split a line holding both a timestamp and a value into (timestamp, value) text, with the
+// value either last (`2025年6月2日 21:28 72`) or first (`72 2025-06-02T21:28:00Z`)
+fn split_combined_line(line: &str) -> Option<(&str, &str)> {
+    static PATTERNS: std::sync::OnceLock<[regex::Regex; 2]> = std::sync::OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| {
+        [
+            regex::Regex::new(r"^(?P<timestamp>.+?)[\s,;]+(?P<value>\d+(?:[.,]\d+)?)$").unwrap(),
+            regex::Regex::new(r"^(?P<value>\d+(?:[.,]\d+)?)[\s,;]+(?P<timestamp>.+)$").unwrap(),
+        ]
+    });
+
+    patterns.iter().find_map(|pattern| {
+        let caps = pattern.captures(line)?;
+        Some((caps.name("timestamp")?.as_str(), caps.name("value")?.as_str()))
+    })
+}
+
+// Describe how values and timestamps are laid out: alternating, values_first,
+// timestamps_first, mixed, or empty
+fn detect_layout(order: &[EntryKind]) -> &'static str {
+    if order.is_empty() {
+        return "empty";
+    }
+    if order.windows(2).all(|pair| pair[0] != pair[1]) {
+        return "alternating";
+    }
+    let switches = order.windows(2).filter(|pair| pair[0] != pair[1]).count();
+    match (switches, order[0]) {
+        (0 | 1, EntryKind::Value) => "values_first",
+        (0 | 1, EntryKind::Timestamp) => "timestamps_first",
+        _ => "mixed",
+    }
+}
+
+// Flatten a JSON body of `[{"<value key>": 72, "<time key>": "..."}, ...]` into alternating
+// value/timestamp lines; objects missing either key are skipped
+fn json_body_to_lines(text: &str, options: &ParseOptions) -> Result<String, Box<dyn std::error::Error>> {
+    let items: Vec<serde_json::Map<String, serde_json::Value>> = serde_json::from_str(text)
+        .map_err(|e| format!("expected a JSON array of objects: {}", e))?;
+
+    let field = |item: &serde_json::Map<String, serde_json::Value>, keys: &[String]| {
+        keys.iter().find_map(|key| match item.get(key) {
+            Some(serde_json::Value::String(text)) => Some(text.clone()),
+            Some(serde_json::Value::Number(number)) => Some(number.to_string()),
+            _ => None,
+        })
+    };
+
+    let mut lines = String::new();
+    for item in &items {
+        match (field(item, &options.value_keys), field(item, &options.time_keys)) {
+            (Some(value), Some(time)) => {
+                lines.push_str(&value);
+                lines.push('\n');
+                lines.push_str(&time);
+                lines.push('\n');
+            }
+            _ => warn!(
+                "Skipping JSON object without a value and timestamp key: {}",
+                Redact(serde_json::Value::Object(item.clone()))
+            ),
+        }
+    }
+    Ok(lines)
+}
+
+// Fixed heart rate data parsing function
+fn parse_heart_rate_data(
+    text: &str,
+    options: &ParseOptions,
+) -> Result<ParsedData, Box<dyn std::error::Error>> {
+    if options.json_input {
+        let lines = json_body_to_lines(text, options)?;
+        let options = ParseOptions {
+            json_input: false,
+            strategy: PairingStrategy::Alternating,
+            ..options.clone()
+        };
+        return parse_heart_rate_data(&lines, &options);
+    }
+
+    // Non-empty lines with their 1-based line numbers in the body and the kind their label names
+    let lines: Vec<(usize, Option<EntryKind>, &str)> = text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, strip_line_prefix(line.trim(), &options.strip_prefixes)))
+        .map(|(i, line)| {
+            let (label, line) = strip_line_label(line, options);
+            (i, label, line)
+        })
+        .filter(|(_, _, line)| !line.is_empty())  // Filter empty lines
+        .collect();
+
+    let mut records = Vec::new();
+    // None marks a gap sentinel, kept so later values still pair with the right timestamps
+    let mut heart_rates: Vec<Option<ParsedValue>> = Vec::new();
+    let mut timestamps = Vec::new();
+    // Kind of each value/timestamp line in input order, for the pairing strategy
+    let mut order = Vec::new();
+    let mut gap_count = 0;
+    let mut ambiguous_count = 0;
+    let mut nonexistent_count = 0;
+    let mut device_id = None;
+    let mut line_stats = LineStats { total: lines.len(), ..Default::default() };
+    let heart_rate_range = options.min_heart_rate..=options.max_heart_rate;
+
+    info!("Total non-empty lines: {}", lines.len());
+
+    // Step 1: Collect heart rate values and timestamps separately
+    for &(i, label, line) in &lines {
+        // A labeled line is only tried as what its label names
+        let may_be_value = label != Some(EntryKind::Timestamp);
+        let may_be_timestamp = label != Some(EntryKind::Value);
+
+        let (value_text, confidence) = if options.parse_confidence {
+            split_confidence(line)
+        } else {
+            (line, None)
+        };
+
+        // Try to parse as heart rate value (number)
+        if let Some(raw_value) = parse_number(value_text, options.decimal_comma).filter(|_| may_be_value) {
+            // Sentinels are the exporter's raw markers, so match them before transforming
+            if options.gap_sentinels.contains(&raw_value) {
+                heart_rates.push(None);
+                order.push(EntryKind::Value);
+                gap_count += 1;
+                line_stats.gaps += 1;
+                debug!("Found gap sentinel {} at line {}", Redact(raw_value), i);
+                continue;
+            }
+
+            let heart_rate = options.value_transform.apply(raw_value);
+
+            // Check reasonable heart rate range (30-220 BPM by default)
+            if heart_rate_range.contains(&heart_rate) {
+                heart_rates.push(Some(ParsedValue { value: heart_rate, range: None, confidence, line_number: i }));
+                order.push(EntryKind::Value);
+                line_stats.values += 1;
+                debug!("Found heart rate: {} at line {}", Redact(heart_rate), i);
+                continue;
+            }
+        }
+
+        // Try to parse as a range, e.g. 68-74; both ends must be in range
+        if options.range_mode != RangeMode::Off && may_be_value {
+            if let Some((low, high)) = parse_value_range(value_text, options.decimal_comma) {
+                let (low, high) = (options.value_transform.apply(low), options.value_transform.apply(high));
+                let (low, high) = (low.min(high), low.max(high));
+                if heart_rate_range.contains(&low) && heart_rate_range.contains(&high) {
+                    let midpoint = (low + high) / 2.0;
+                    heart_rates.push(Some(ParsedValue { value: midpoint, range: Some((low, high)), confidence, line_number: i }));
+                    order.push(EntryKind::Value);
+                    line_stats.ranges += 1;
+                    debug!("Found heart rate range: {}-{} (midpoint {}) at line {}", Redact(low), Redact(high), Redact(midpoint), i);
+                    continue;
+                }
+            }
+        }
+
+        // Timestamp and value on the same line; checked before plain timestamps, which would
+        // ignore the trailing value. The value goes first so it pairs with this timestamp.
+        if let Some((timestamp_text, value_text)) = split_combined_line(line).filter(|_| label.is_none()) {
+            let heart_rate = parse_number(value_text, options.decimal_comma)
+                .map(|raw_value| options.value_transform.apply(raw_value))
+                .filter(|heart_rate| heart_rate_range.contains(heart_rate));
+            let timestamp = parse_any_timestamp(timestamp_text, options.local_tz, &options.timestamp_formats);
+            if let (Some(heart_rate), Some((timestamp, resolution, format))) = (heart_rate, timestamp) {
+                heart_rates.push(Some(ParsedValue { value: heart_rate, range: None, confidence: None, line_number: i }));
+                timestamps.push(timestamp);
+                order.extend([EntryKind::Value, EntryKind::Timestamp]);
+                line_stats.combined_lines += 1;
+                line_stats.count_timestamp(format);
+                match resolution {
+                    LocalResolution::Exact => {}
+                    LocalResolution::Ambiguous => ambiguous_count += 1,
+                    LocalResolution::Nonexistent => nonexistent_count += 1,
+                }
+                debug!("Found heart rate {} with timestamp {} at line {}", Redact(heart_rate), Redact(timestamp), i);
+                continue;
+            }
+        }
+
+        // Try to parse as timestamp
+        let timestamp = parse_any_timestamp(line, options.local_tz, &options.timestamp_formats).filter(|_| may_be_timestamp);
+        if let Some((timestamp, resolution, format)) = timestamp {
+            line_stats.count_timestamp(format);
+            match resolution {
+                LocalResolution::Exact => {}
+                LocalResolution::Ambiguous => ambiguous_count += 1,
+                LocalResolution::Nonexistent => nonexistent_count += 1,
+            }
+            timestamps.push(timestamp);
+            order.push(EntryKind::Timestamp);
+            debug!("Found timestamp: {} at line {}", Redact(timestamp), i);
+            continue;
+        }
+
+        // Device line, e.g. `Device: watch-123`
+        if let Some(pattern) = &options.device_line_pattern {
+            if let Some(found) = pattern.captures(line).and_then(|caps| caps.get(1)) {
+                let found = found.as_str().trim();
+                if !found.is_empty() {
+                    debug!("Found device line '{}' at line {}", found, i);
+                    device_id.get_or_insert_with(|| found.to_string());
+                    line_stats.device_lines += 1;
+                    continue;
+                }
+            }
+        }
+
+        // If neither heart rate nor timestamp, print warning
+        warn!("Could not parse line {}: '{}'", i, Redact(line));
+        line_stats.unparsed += 1;
+        if line_stats.unparsed_samples.len() < UNPARSED_SAMPLE_LIMIT {
+            line_stats.unparsed_samples.push(line.to_string());
+        }
+    }
+
+    info!("Found {} heart rates and {} timestamps", heart_rates.len(), timestamps.len());
+
+    // Step 2: Pair heart rates and timestamps using the requested strategy; synthesized
+    // timestamps line up with the values one to one
+    let pairs = match options.synthesize_timestamps {
+        Some(synthesized) if timestamps.is_empty() && !heart_rates.is_empty() => {
+            timestamps = synthesized
+                .timestamps(heart_rates.len())
+                .ok_or("interval_secs is too large to synthesize timestamps for this many values")?;
+            info!(
+                "Synthesized {} timestamps {}s apart ending at {}",
+                timestamps.len(),
+                synthesized.interval.num_seconds(),
+                synthesized.end
+            );
+            (0..heart_rates.len()).map(|i| (i, i)).collect()
+        }
+        _ => pair_entries(&order, options.strategy),
+    };
+    let layout = detect_layout(&order);
+
+    // Nothing usable (e.g. every value out of range); the caller decides how to report it
+    if pairs.is_empty() {
+        info!("No valid heart rate and timestamp pairs found");
+        return Ok(ParsedData { records, gap_count, ambiguous_count, nonexistent_count, device_id, line_stats, layout });
+    }
+
+    // Based on data format, there might be several pairing methods:
+    // 1. Heart rates and timestamps appear alternately in sequence
+    // 2. All heart rates first, all timestamps after
+    // 3. All timestamps first, all heart rates after
+    // Sequential pairing handles all three; `strategy` forces one layout when it doesn't fit
+    if pairs.len() < heart_rates.len().max(timestamps.len()) {
+        info!("{:?} pairing left {} values and {} timestamps unpaired", options.strategy, heart_rates.len() - pairs.len(), timestamps.len() - pairs.len());
+    }
+
+    for (value_index, timestamp_index) in pairs {
+        let timestamp: DateTime<FixedOffset> = timestamps[timestamp_index];
+        let Some(parsed) = heart_rates[value_index] else {
+            continue;
+        };
+        let Some(corrected) = timestamp.with_timezone(&Utc).checked_add_signed(options.clock_offset) else {
+            warn!("Skipping record at {}: clock offset moves it out of range", Redact(timestamp));
+            continue;
+        };
+        records.push(HeartRateRecord {
+            value: parsed.value,
+            timestamp: corrected,
+            offset_minutes: timestamp.offset().local_minus_utc() / 60,
+            delta: None,
+            range: parsed.range.filter(|_| options.range_mode == RangeMode::MinMax),
+            confidence: parsed.confidence,
+            source_line: Some(parsed.line_number).filter(|_| options.with_source_line),
+        });
+    }
+
+    // Sort by timestamp to ensure data is in chronological order, unless the source order was asked for
+    if !options.preserve_order {
+        records.sort_by_key(|record| record.timestamp);
+    }
+
+    info!("Successfully created {} heart rate records", records.len());
+
+    // Print first few records for debugging
+    for (i, record) in records.iter().take(5).enumerate() {
+        debug!("Record {}: {} BPM at {}", i + 1, Redact(record.value), Redact(record.timestamp));
+    }
+
+    Ok(ParsedData { records, gap_count, ambiguous_count, nonexistent_count, device_id, line_stats, layout })
+}
+
+// Why a budgeted parse produced no data
+#[derive(Debug)]
+enum ParseFailure {
+    Invalid(String),
+    TimedOut(Duration),
+}
+
+impl ParseFailure {
+    fn into_http_error(self) -> (StatusCode, String) {
+        match self {
+            ParseFailure::Invalid(e) => (StatusCode::BAD_REQUEST, format!("Parse error: {}", e)),
+            ParseFailure::TimedOut(budget) => (
+                StatusCode::REQUEST_TIMEOUT,
+                format!("Parsing exceeded the {} ms budget", budget.as_millis()),
+            ),
+        }
+    }
+}
+
+// Parse on the blocking pool so a pathological body can't stall the runtime, giving up
+// after PARSE_TIMEOUT_MS. A timed-out parse runs to completion in the background and is discarded.
+async fn parse_with_budget(
+    text: Arc<str>,
+    options: ParseOptions,
+    budget: Option<Duration>,
+) -> Result<ParsedData, ParseFailure> {
+    run_with_budget(budget, move || parse_heart_rate_data(&text, &options).map_err(|e| e.to_string())).await
+}
+
+// The budget half of parse_with_budget, taking the parser so tests can pass one that stalls
+async fn run_with_budget(
+    budget: Option<Duration>,
+    parser: impl FnOnce() -> Result<ParsedData, String> + Send + 'static,
+) -> Result<ParsedData, ParseFailure> {
+    let parse = tokio::task::spawn_blocking(parser);
+
+    let joined = match budget {
+        Some(budget) => tokio::time::timeout(budget, parse)
+            .await
+            .map_err(|_| ParseFailure::TimedOut(budget))?,
+        None => parse.await,
+    };
+
+    joined
+        .map_err(|e| ParseFailure::Invalid(format!("parser failed: {}", e)))?
+        .map_err(ParseFailure::Invalid)
+}
+
+// Parse Chinese datetime format: 2025年6月2日 21:28
+// Also 12-hour forms with 上午 (AM) / 下午 (PM): 2025年6月2日 下午9:28
+fn parse_chinese_datetime(datetime_str: &str) -> Option<NaiveDateTime> {
+    // Use regex to parse Chinese date format
+    let re = regex::Regex::new(
+        r"(\d{4})年(\d{1,2})月(\d{1,2})日(?:\s*(上午|下午)\s*|\s+)(\d{1,2}):(\d{2})"
+    ).ok()?;
+
+    if let Some(caps) = re.captures(datetime_str) {
+        let year: i32 = caps[1].parse().ok()?;
+        let month: u32 = caps[2].parse().ok()?;
+        let day: u32 = caps[3].parse().ok()?;
+        let mut hour: u32 = caps[5].parse().ok()?;
+        let minute: u32 = caps[6].parse().ok()?;
+
+        // 12-hour clock: 上午12点 is midnight, 下午12点 is noon
+        if let Some(period) = caps.get(4) {
+            if !(1..=12).contains(&hour) {
+                return None;
+            }
+            hour = match (period.as_str(), hour) {
+                ("上午", 12) => 0,
+                ("下午", 12) => 12,
+                ("下午", hour) => hour + 12,
+                (_, hour) => hour,
+            };
+        }
+
+        // Local wall-clock time; resolve_local_time attaches the timezone
+        return Some(NaiveDateTime::new(
+            chrono::NaiveDate::from_ymd_opt(year, month, day)?,
+            chrono::NaiveTime::from_hms_opt(hour, minute, 0)?
+        ));
+    }
+
+    None
+}
+
+// Parse RFC 3339 / ISO 8601 timestamps, keeping sub-second (down to ns) resolution:
+// 2025-06-02T21:28:00.123456789Z, 2025-06-02T21:28:00+08:00
+fn parse_iso_datetime(datetime_str: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(datetime_str)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+// How a naive local time mapped onto the timezone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LocalResolution {
+    Exact,
+    // Repeated wall-clock time (DST fall-back); the earlier instant is used
+    Ambiguous,
+    // Skipped wall-clock time (DST spring-forward); read with the pre-transition offset
+    Nonexistent,
+}
+
+// Attach a timezone to a naive local time. Without a named timezone the time is UTC+8.
+// DST policy: ambiguous times take the earlier (pre-transition) offset, and times inside
+// a spring-forward gap are read with the offset in effect before the gap, which lands
+// them the same distance past the transition (02:30 in a 02:00->03:00 gap becomes 03:30).
+fn resolve_local_time(
+    naive: NaiveDateTime,
+    tz: Option<chrono_tz::Tz>,
+) -> Option<(DateTime<FixedOffset>, LocalResolution)> {
+    use chrono::offset::{LocalResult, Offset};
+
+    let tz = match tz {
+        Some(tz) => tz,
+        None => {
+            let time = naive.and_local_timezone(china_offset()).single()?;
+            return Some((time, LocalResolution::Exact));
+        }
+    };
+
+    let (time, resolution) = match tz.from_local_datetime(&naive) {
+        LocalResult::Single(time) => (time, LocalResolution::Exact),
+        LocalResult::Ambiguous(earlier, _) => (earlier, LocalResolution::Ambiguous),
+        LocalResult::None => {
+            let before = tz.from_local_datetime(&(naive - chrono::Duration::hours(3))).earliest()?;
+            let utc = naive - chrono::Duration::seconds(before.offset().fix().local_minus_utc() as i64);
+            (tz.from_utc_datetime(&utc), LocalResolution::Nonexistent)
+        }
+    };
+
+    Some((time.with_timezone(&time.offset().fix()), resolution))
+}
+
+// Parse ISO 8601 date-times without an offset: 2025-06-02 21:28, 2025-06-02T21:28:00.5
+fn parse_iso_local_datetime(datetime_str: &str) -> Option<NaiveDateTime> {
+    const FORMATS: [&str; 4] = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"];
+
+    FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(datetime_str, format).ok())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimestampFormat {
+    // 2025年6月2日 21:28, local time
+    Chinese,
+    // 2025-06-02T21:28:00+08:00, carries its own offset
+    Rfc3339,
+    // 2025-06-02 21:28:00, local time
+    IsoLocal,
+}
+
+impl TimestampFormat {
+    // Default order when TIMESTAMP_FORMATS is unset
+    const ALL: [TimestampFormat; 3] = [TimestampFormat::Chinese, TimestampFormat::Rfc3339, TimestampFormat::IsoLocal];
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "chinese" => Some(TimestampFormat::Chinese),
+            "rfc3339" => Some(TimestampFormat::Rfc3339),
+            "iso_local" => Some(TimestampFormat::IsoLocal),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            TimestampFormat::Chinese => "chinese",
+            TimestampFormat::Rfc3339 => "rfc3339",
+            TimestampFormat::IsoLocal => "iso_local",
+        }
+    }
+}
+
+// Try each format in order, keeping the offset the time was expressed in
+fn parse_any_timestamp(
+    datetime_str: &str,
+    local_tz: Option<chrono_tz::Tz>,
+    formats: &[TimestampFormat],
+) -> Option<(DateTime<FixedOffset>, LocalResolution, TimestampFormat)> {
+    formats.iter().find_map(|&format| {
+        let (time, resolution) = match format {
+            TimestampFormat::Chinese => resolve_local_time(parse_chinese_datetime(datetime_str)?, local_tz)?,
+            TimestampFormat::Rfc3339 => (DateTime::parse_from_rfc3339(datetime_str).ok()?, LocalResolution::Exact),
+            TimestampFormat::IsoLocal => resolve_local_time(parse_iso_local_datetime(datetime_str)?, local_tz)?,
+        };
+        Some((time, resolution, format))
+    })
+}
+
+// Local timestamps are assumed to be UTC+8
+fn china_offset() -> FixedOffset {
+    FixedOffset::east_opt(8 * 3600).unwrap()
+}
+
+// Timestamp precision used for line protocol and the write URL. Parsed timestamps are full
+// instants before precision applies: fields a format lacks are zero, so `2025年6月2日 21:28`
+// (UTC+8) is 21:28:00.000 and is written as 1748870880000 at ms or 1748870880 at s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Precision {
+    Seconds,
+    #[default]
+    Millis,
+    Micros,
+    Nanos,
+}
+
+impl Precision {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "s" => Some(Precision::Seconds),
+            "ms" => Some(Precision::Millis),
+            "us" => Some(Precision::Micros),
+            "ns" => Some(Precision::Nanos),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Precision::Seconds => "s",
+            Precision::Millis => "ms",
+            Precision::Micros => "us",
+            Precision::Nanos => "ns",
+        }
+    }
+
+    // Smallest timestamp step representable at this precision
+    fn unit(&self) -> chrono::Duration {
+        match self {
+            Precision::Seconds => chrono::Duration::seconds(1),
+            Precision::Millis => chrono::Duration::milliseconds(1),
+            Precision::Micros => chrono::Duration::microseconds(1),
+            Precision::Nanos => chrono::Duration::nanoseconds(1),
+        }
+    }
+
+    // Whether writing `time` at this precision drops part of it
+    fn truncates(&self, time: &DateTime<Utc>) -> bool {
+        let nanos_per_unit = self.unit().num_nanoseconds().unwrap_or(1) as u32;
+        !time.timestamp_subsec_nanos().is_multiple_of(nanos_per_unit)
+    }
+
+    // Timestamp in this precision's units since the epoch; sub-unit parts are truncated.
+    // Saturates instead of failing for dates outside the i64 nanosecond range.
+    fn timestamp(&self, time: &DateTime<Utc>) -> i64 {
+        let (units_per_second, nanos_per_unit) = match self {
+            Precision::Seconds => (1, 1_000_000_000),
+            Precision::Millis => (1_000, 1_000_000),
+            Precision::Micros => (1_000_000, 1_000),
+            Precision::Nanos => (1_000_000_000, 1),
+        };
+        time.timestamp()
+            .saturating_mul(units_per_second)
+            .saturating_add((time.timestamp_subsec_nanos() / nanos_per_unit) as i64)
+    }
+}
+
+// Set each record's delta from the previous record; the first record gets 0.
+// Records must already be in their final order.
+fn compute_deltas(records: &mut [HeartRateRecord]) {
+    let mut previous = None;
+    for record in records.iter_mut() {
+        record.delta = Some(previous.map_or(0.0, |previous| record.value - previous));
+        previous = Some(record.value);
+    }
+}
+
+// Keep records sharing a timestamp apart by nudging each later duplicate one `step` past
+// the previous record, so GreptimeDB stores all of them instead of the last one winning.
+// Expects sorted records; returns how many timestamps were moved.
+fn disambiguate_duplicates(records: &mut [HeartRateRecord], step: chrono::Duration) -> usize {
+    let mut moved = 0;
+    let mut previous: Option<(DateTime<Utc>, DateTime<Utc>)> = None; // (original, assigned)
+
+    for record in records.iter_mut() {
+        let original = record.timestamp;
+        if let Some((previous_original, previous_assigned)) = previous {
+            if original == previous_original {
+                record.timestamp = previous_assigned + step;
+                moved += 1;
+            }
+        }
+        previous = Some((original, record.timestamp));
+    }
+
+    moved
+}
+
+// Outlier filter applied to the sorted records, from `filter=median:5` or `filter=mean:5`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutlierFilter {
+    // Drop samples deviating from the median of a centered window by more than the max deviation
+    Median(usize),
+    // Replace each sample with the mean of a centered window
+    Mean(usize),
+}
+
+impl OutlierFilter {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let (kind, window) = spec
+            .split_once(':')
+            .ok_or_else(|| format!("expected <median|mean>:<window>, got '{}'", spec))?;
+        let window: usize = window
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid window size '{}'", window))?;
+        if window < 3 {
+            return Err("window size must be at least 3".to_string());
+        }
+
+        match kind.trim() {
+            "median" => Ok(OutlierFilter::Median(window)),
+            "mean" => Ok(OutlierFilter::Mean(window)),
+            other => Err(format!("unknown filter '{}'", other)),
+        }
+    }
+
+    // Returns the filtered records and how many were dropped (median) or changed (mean).
+    // Windows are computed over the original values and shrink at the edges.
+    fn apply(&self, mut records: Vec<HeartRateRecord>, max_deviation: f64) -> (Vec<HeartRateRecord>, usize) {
+        let values: Vec<f64> = records.iter().map(|record| record.value).collect();
+        let window_of = |i: usize, window: usize| {
+            let half = window / 2;
+            &values[i.saturating_sub(half)..(i + half + 1).min(values.len())]
+        };
+
+        match *self {
+            OutlierFilter::Median(window) => {
+                let keep: Vec<bool> = (0..values.len())
+                    .map(|i| (values[i] - median(window_of(i, window))).abs() <= max_deviation)
+                    .collect();
+                let before = records.len();
+                let mut keep = keep.into_iter();
+                records.retain(|_| keep.next().unwrap_or(true));
+                let dropped = before - records.len();
+                (records, dropped)
+            }
+            OutlierFilter::Mean(window) => {
+                let mut changed = 0;
+                for (i, record) in records.iter_mut().enumerate() {
+                    let neighbours = window_of(i, window);
+                    let mean = neighbours.iter().sum::<f64>() / neighbours.len() as f64;
+                    if mean != record.value {
+                        record.value = mean;
+                        changed += 1;
+                    }
+                }
+                (records, changed)
+            }
+        }
+    }
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+// Count values into buckets of `width` aligned to multiples of the width, e.g. [70, 80).
+// Only non-empty buckets are returned, in ascending order.
+fn build_histogram(values: impl IntoIterator<Item = f64>, width: f64) -> Vec<HistogramBucket> {
+    let mut counts: std::collections::BTreeMap<i64, usize> = std::collections::BTreeMap::new();
+    for value in values {
+        *counts.entry((value / width).floor() as i64).or_insert(0) += 1;
+    }
+
+    counts
+        .into_iter()
+        .map(|(index, count)| HistogramBucket {
+            start: index as f64 * width,
+            end: (index + 1) as f64 * width,
+            count,
+        })
+        .collect()
+}
+
+// Convert to InfluxDB Line Protocol format
+// Tags are written in the given order, e.g. [("device_id", "watch"), ("user_id", "alice")]
+fn to_influxdb_line(
+    record: &HeartRateRecord,
+    tags: &[(&str, &str)],
+    config: &Config,
+    options: &LineOptions,
+) -> String {
+    let timestamp = options.precision.timestamp(&record.timestamp);
+
+    let mut tag_set: String = tags
+        .iter()
+        .map(|(key, value)| format!(",{}={}", key, escape_tag(value)))
+        .collect();
+    if options.with_zone {
+        tag_set.push_str(&format!(",zone={}", config.hr_zones.zone(record.value)));
+    }
+
+    let mut field_set = format!("value={}", format_value(record.value, config));
+    if let Some((min, max)) = record.range {
+        field_set.push_str(&format!(
+            ",min={},max={}",
+            format_value(min, config),
+            format_value(max, config)
+        ));
+    }
+    if let Some(confidence) = record.confidence {
+        field_set.push_str(&format!(",confidence={}", confidence));
+    }
+    if let Some(source_line) = record.source_line {
+        field_set.push_str(&format!(",source_line={}i", source_line));
+    }
+    if let Some(delta) = record.delta {
+        field_set.push_str(&format!(",delta={}", format_value(delta, config)));
+    }
+    if options.store_offset {
+        field_set.push_str(&format!(",offset_minutes={}i", record.offset_minutes));
+    }
+    if let Some(ingested_at) = options.ingested_at {
+        field_set.push_str(&format!(",ingested_at={}i", ingested_at.timestamp_millis()));
+    }
+
+    format!(
+        "{}{} {} {}",
+        options.measurement,
+        tag_set,
+        field_set,
+        timestamp
+    )
+}
+
+// device_id tag used when neither the request nor the body names a device
+const DEFAULT_DEVICE_ID: &str = "apple-watch";
+
+const DEFAULT_MEASUREMENT: &str = "heart_rate";
+
+// Per-device table name, e.g. heart_rate_watch1; characters outside [A-Za-z0-9_] become '_'
+fn per_device_measurement(base: &str, device_id: &str) -> String {
+    let suffix: String = device_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    format!("{}_{}", base, suffix)
+}
+
+// Escape a tag value for line protocol
+fn escape_tag(value: &str) -> String {
+    value.replace(" ", "\\ ").replace(",", "\\,").replace("=", "\\=")
+}
+
+// Escape a line-protocol string field value
+fn escape_field_string(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Audit line for an uploaded body: its hash and size, the records it produced, and
+// where the full text was kept when UPLOAD_AUDIT_BLOB_DIR is set
+async fn upload_audit_line(
+    config: &Config,
+    text: &str,
+    tags: &[(&str, &str)],
+    record_count: usize,
+    precision: Precision,
+) -> Result<String, String> {
+    let hash = sha256_hex(text.as_bytes());
+
+    let blob_field = match &config.upload_audit_blob_dir {
+        Some(dir) => {
+            let path = std::path::Path::new(dir).join(format!("{}.txt", hash));
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+            tokio::fs::write(&path, text)
+                .await
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            format!(",blob_path=\"{}\"", escape_field_string(&path.to_string_lossy()))
+        }
+        None => String::new(),
+    };
+
+    let tag_set: String = tags
+        .iter()
+        .map(|(key, value)| format!(",{}={}", key, escape_tag(value)))
+        .collect();
+
+    Ok(format!(
+        "upload_audit{} sha256=\"{}\",bytes={}i,record_count={}i{} {}",
+        tag_set,
+        hash,
+        text.len(),
+        record_count,
+        blob_field,
+        precision.timestamp(&Utc::now())
+    ))
+}
+
+// Distinct parameter names in a raw query string, sorted
+fn query_keys(raw_query: Option<&str>) -> Vec<&str> {
+    let mut keys: Vec<&str> = raw_query
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').map_or(pair, |(key, _)| key))
+        .collect();
+    keys.sort_unstable();
+    keys.dedup();
+    keys
+}
+
+// In strict mode, reject query parameters outside `known` so typos like `deviceid` don't fall back to defaults
+fn reject_unknown_params(config: &Config, raw_query: Option<&str>, known: &[&str]) -> Result<(), (StatusCode, String)> {
+    if !config.strict_query_params {
+        return Ok(());
+    }
+
+    let unknown: Vec<&str> = query_keys(raw_query)
+        .into_iter()
+        .filter(|key| !known.contains(key))
+        .collect();
+
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::BAD_REQUEST,
+            format!("Unknown query parameter(s): {}", unknown.join(", ")),
+        ))
+    }
+}
+
+// Validate a user_id tag: 1-64 characters of [A-Za-z0-9_.@-]
+fn validate_user_id(user_id: &str) -> Result<(), String> {
+    if user_id.is_empty() || user_id.len() > 64 {
+        return Err("user_id must be 1-64 characters long".to_string());
+    }
+    if !user_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '@' | '-')) {
+        return Err("user_id may only contain letters, digits, '_', '.', '@' and '-'".to_string());
+    }
+    Ok(())
+}
+
+// Validate a measurement name: 1-64 characters of [A-Za-z0-9_], not starting with a digit
+fn validate_measurement(measurement: &str) -> Result<(), String> {
+    if measurement.is_empty() || measurement.len() > 64 {
+        return Err("measurement must be 1-64 characters long".to_string());
+    }
+    if !measurement.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err("measurement may only contain letters, digits and '_'".to_string());
+    }
+    if measurement.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err("measurement must not start with a digit".to_string());
+    }
+    Ok(())
+}
+
+// Check a requested measurement: 400 when malformed, 403 when ALLOWED_MEASUREMENTS is set and doesn't list it
+fn check_measurement(config: &Config, measurement: &str) -> Result<(), (StatusCode, String)> {
+    validate_measurement(measurement)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid measurement: {}", e)))?;
+    if !config.allowed_measurements.is_empty() && !config.allowed_measurements.iter().any(|allowed| allowed == measurement) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("Measurement '{}' is not allowed; allowed: {}", measurement, config.allowed_measurements.join(", ")),
+        ));
+    }
+    Ok(())
+}
+
+// Format a field value; integers use the line-protocol `i` suffix.
+// Rounding is half away from zero, so 72.5 becomes 73i.
+// Floats are written with a fixed number of decimals (HR_DECIMALS).
+fn format_value(value: f64, config: &Config) -> String {
+    if config.hr_as_integer {
+        format!("{}i", value.round() as i64)
+    } else {
+        format!("{:.*}", config.hr_decimals, value)
+    }
+}
+
+// Where a successfully written batch ended up
+#[derive(Debug)]
+enum Delivery {
+    // The configured sink
+    Primary,
+    // The dead-letter file, after the primary write failed with `error`
+    Fallback { error: WriteError },
+}
+
+type WriteFuture<'a> = Pin<Box<dyn Future<Output = Result<Delivery, WriteError>> + Send + 'a>>;
+
+// Destination for flushed line-protocol batches
+trait Writer: Send + Sync + std::fmt::Debug {
+    fn write<'a>(&'a self, app_state: &'a AppState, lines: Vec<String>, precision: Precision) -> WriteFuture<'a>;
+}
+
+// Writes to GreptimeDB over HTTP (the default sink)
+#[derive(Debug)]
+struct GreptimeWriter;
+
+impl Writer for GreptimeWriter {
+    fn write<'a>(&'a self, app_state: &'a AppState, lines: Vec<String>, precision: Precision) -> WriteFuture<'a> {
+        Box::pin(async move {
+            send_to_greptime(app_state, lines, precision).await?;
+            Ok(Delivery::Primary)
+        })
+    }
+}
+
+// Appends line protocol to a local file, for CI and development without GreptimeDB
+#[derive(Debug)]
+struct FileWriter {
+    path: String,
+    // Serializes appends so batches never interleave
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl FileWriter {
+    fn new(path: String) -> Self {
+        Self { path, lock: tokio::sync::Mutex::new(()) }
+    }
+
+    async fn append(&self, lines: Vec<String>) -> Result<(), WriteError> {
+        use tokio::io::AsyncWriteExt;
+
+        let _guard = self.lock.lock().await;
+        // Always newline-terminated so the next append starts on its own line
+        let content = line_protocol_body(&lines, true);
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| WriteError::Request(format!("Failed to open {}: {}", self.path, e)))?;
+        file.write_all(content.as_bytes())
+            .await
+            .map_err(|e| WriteError::Request(format!("Failed to write {}: {}", self.path, e)))?;
+        // tokio finishes file writes in the background; flush so errors are reported here
+        // and the batch is on disk before the write is acknowledged
+        file.flush()
+            .await
+            .map_err(|e| WriteError::Request(format!("Failed to write {}: {}", self.path, e)))?;
+
+        info!("Wrote {} lines to {}", lines.len(), self.path);
+        Ok(())
+    }
+}
+
+// Lines are appended as-is; the file doesn't record their precision
+impl Writer for FileWriter {
+    fn write<'a>(&'a self, _app_state: &'a AppState, lines: Vec<String>, _precision: Precision) -> WriteFuture<'a> {
+        Box::pin(async move {
+            self.append(lines).await?;
+            Ok(Delivery::Primary)
+        })
+    }
+}
+
+// Writes to GreptimeDB and dead-letters failed batches to a file, so nothing is lost
+#[derive(Debug)]
+struct FallbackWriter {
+    primary: Box<dyn Writer>,
+    fallback: FileWriter,
+}
+
+impl FallbackWriter {
+    async fn write_with_fallback(
+        &self,
+        app_state: &AppState,
+        lines: Vec<String>,
+        precision: Precision,
+    ) -> Result<Delivery, WriteError> {
+        let primary_error = match self.primary.write(app_state, lines.clone(), precision).await {
+            Ok(delivery) => return Ok(delivery),
+            Err(e) => e,
+        };
+
+        warn!(
+            "GreptimeDB write failed ({}), writing {} lines to {}",
+            primary_error.for_log(),
+            lines.len(),
+            self.fallback.path
+        );
+        let count = lines.len() as u64;
+        if let Err(e) = self.fallback.append(lines).await {
+            error!("Fallback write failed too: {}", e);
+            // Report the original failure; the file error is only logged
+            return Err(primary_error);
+        }
+        app_state.metrics.fallback_lines_total.inc_by(count);
+        Ok(Delivery::Fallback { error: primary_error })
+    }
+}
+
+impl Writer for FallbackWriter {
+    fn write<'a>(&'a self, app_state: &'a AppState, lines: Vec<String>, precision: Precision) -> WriteFuture<'a> {
+        Box::pin(self.write_with_fallback(app_state, lines, precision))
+    }
+}
+
+// Writes to GreptimeDB's OpenTSDB endpoint (WRITE_BACKEND=opentsdb)
+#[derive(Debug)]
+struct OpenTsdbWriter;
+
+impl OpenTsdbWriter {
+    async fn put(&self, app_state: &AppState, lines: Vec<String>, precision: Precision) -> Result<(), WriteError> {
+        let points: Vec<serde_json::Value> = lines
+            .iter()
+            .flat_map(|line| opentsdb_data_points(line, precision))
+            .collect();
+        if points.is_empty() {
+            return Err(WriteError::Unconvertible(format!(
+                "None of the {} lines could be converted to OpenTSDB data points",
+                lines.len()
+            )));
+        }
+
+        let url = opentsdb_url(&app_state.config());
+        info!("Sending {} OpenTSDB data points to {}", points.len(), url);
+
+        let started = Instant::now();
+        let response = app_state.greptime_request(reqwest::Method::POST, &url).json(&points).send().await;
+        app_state.metrics.greptime_write_seconds.observe(started.elapsed().as_secs_f64());
+        check_write_response(app_state, response).await
+    }
+}
+
+impl Writer for OpenTsdbWriter {
+    fn write<'a>(&'a self, app_state: &'a AppState, lines: Vec<String>, precision: Precision) -> WriteFuture<'a> {
+        Box::pin(async move {
+            self.put(app_state, lines, precision).await?;
+            Ok(Delivery::Primary)
+        })
+    }
+}
+
+// Split at `separator` outside of backslash escapes and double-quoted strings
+fn split_line_protocol(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn unescape_line_protocol(text: &str) -> String {
+    text.replace("\\ ", " ").replace("\\,", ",").replace("\\=", "=")
+}
+
+// OpenTSDB JSON put endpoint of the configured GreptimeDB instance
+fn opentsdb_url(config: &Config) -> String {
+    format!("{}/v1/opentsdb/api/put?db={}", config.greptime_url, encode_query_value(&config.greptime_db))
+}
+
+// OpenTSDB data points for one line-protocol line, one per numeric field: the `value` field
+// becomes metric <measurement>, any other field <measurement>_<field>. String fields are skipped.
+// Timestamps finer than milliseconds are truncated to milliseconds, which OpenTSDB expects.
+fn opentsdb_data_points(line: &str, precision: Precision) -> Vec<serde_json::Value> {
+    let sections = split_line_protocol(line.trim(), ' ');
+    let [series, fields, timestamp] = sections[..] else {
+        warn!("Skipping line without a timestamp for OpenTSDB: {}", Redact(line));
+        return Vec::new();
+    };
+    let Ok(timestamp) = timestamp.parse::<i64>() else {
+        warn!("Skipping line with invalid timestamp for OpenTSDB: {}", Redact(line));
+        return Vec::new();
+    };
+    let timestamp = match precision {
+        Precision::Seconds | Precision::Millis => timestamp,
+        Precision::Micros => timestamp.div_euclid(1_000),
+        Precision::Nanos => timestamp.div_euclid(1_000_000),
+    };
+
+    let mut series = split_line_protocol(series, ',').into_iter();
+    let measurement = unescape_line_protocol(series.next().unwrap_or_default());
+    let tags: serde_json::Map<String, serde_json::Value> = series
+        .filter_map(|tag| match split_line_protocol(tag, '=')[..] {
+            [key, value] => Some((unescape_line_protocol(key), serde_json::Value::String(unescape_line_protocol(value)))),
+            _ => None,
+        })
+        .collect();
+
+    split_line_protocol(fields, ',')
+        .into_iter()
+        .filter_map(|field| {
+            let (key, value) = field.split_once('=')?;
+            let value: f64 = value.strip_suffix('i').unwrap_or(value).parse().ok()?;
+            let metric = if key == "value" { measurement.clone() } else { format!("{}_{}", measurement, key) };
+            Some(serde_json::json!({
+                "metric": metric,
+                "timestamp": timestamp,
+                "value": value,
+                "tags": tags,
+            }))
+        })
+        .collect()
+}
+
+// Pick the sink from WRITE_SINK (greptime or file) and the GreptimeDB protocol from WRITE_BACKEND;
+// FALLBACK_TO_FILE wraps the GreptimeDB sink
+fn build_writer(config: &Config) -> Arc<dyn Writer> {
+    let greptime = || -> Arc<dyn Writer> {
+        let primary: Box<dyn Writer> = match config.write_backend.as_str() {
+            "opentsdb" => Box::new(OpenTsdbWriter),
+            "influxdb" => Box::new(GreptimeWriter),
+            other => {
+                warn!("Unknown WRITE_BACKEND '{}', using InfluxDB line protocol", other);
+                Box::new(GreptimeWriter)
+            }
+        };
+        if config.fallback_to_file {
+            Arc::new(FallbackWriter {
+                primary,
+                fallback: FileWriter::new(config.sink_path.clone()),
+            })
+        } else {
+            Arc::from(primary)
+        }
+    };
+
+    match config.write_sink.as_str() {
+        "file" => Arc::new(FileWriter::new(config.sink_path.clone())),
+        "greptime" => greptime(),
+        other => {
+            warn!("Unknown WRITE_SINK '{}', writing to GreptimeDB", other);
+            greptime()
+        }
+    }
+}
+
+// Join lines into a line-protocol body: one line per record, no blank lines,
+// and a trailing newline only when requested
+fn line_protocol_body(lines: &[String], trailing_newline: bool) -> String {
+    let mut body = String::new();
+    for line in lines.iter().map(|line| line.trim_end_matches(['\r', '\n'])) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !body.is_empty() {
+            body.push('\n');
+        }
+        body.push_str(line);
+    }
+    if trailing_newline && !body.is_empty() {
+        body.push('\n');
+    }
+    body
+}
+
+// Send data to GreptimeDB, mirroring to GREPTIME_MIRROR_URL when configured.
+// Only the primary result counts; the mirror write runs detached, so a slow mirror never
+// holds up the flusher, and its failures are logged and ignored. At most MAX_MIRROR_WRITES
+// run at once; beyond that the mirror misses the batch.
+async fn send_to_greptime(
+    app_state: &AppState,
+    lines: Vec<String>,
+    precision: Precision,
+) -> Result<(), WriteError> {
+    let body = line_protocol_body(&lines, app_state.config().trailing_newline);
+
+    info!("Sending {} lines of data", lines.len());
+
+    if let Some(mirror_url) = app_state.config().greptime_mirror_url.clone() {
+        match app_state.mirror_permits.clone().try_acquire_owned() {
+            Ok(permit) => {
+                let mirror_state = app_state.clone();
+                let body = body.clone();
+                let mut mirror_writes = app_state.mirror_writes.lock().unwrap();
+                // Forget finished writes so the set only holds running ones
+                while mirror_writes.try_join_next().is_some() {}
+                mirror_writes.spawn(async move {
+                    let _permit = permit;
+                    if let Err(e) = write_lines(&mirror_state, &mirror_url, body, precision).await {
+                        warn!("Mirror write to {} failed: {}", mirror_url, e.for_log());
+                    }
+                });
+            }
+            Err(_) => {
+                warn!(
+                    "Skipped mirror write of {} lines: {} mirror writes already running",
+                    lines.len(),
+                    app_state.config().max_mirror_writes
+                );
+                app_state.metrics.mirror_writes_dropped_total.inc();
+            }
+        }
+    }
+
+    write_primary(app_state, body, precision).await?;
+
+    info!("Successfully sent to GreptimeDB");
+    Ok(())
+}
+
+// Write to the primary instance; with AUTO_CREATE_DB a missing database is created and the write retried once
+async fn write_primary(app_state: &AppState, body: String, precision: Precision) -> Result<(), WriteError> {
+    let base_url = &app_state.config().greptime_url;
+    // Shared by both attempts, so creating the database doesn't reset the budget
+    let deadline = app_state.config().write_deadline.map(|budget| Instant::now() + budget);
+
+    match write_lines_with_retry(app_state, base_url, body.clone(), precision, deadline).await {
+        Err(WriteError::DatabaseNotFound { db }) if app_state.config().auto_create_db => {
+            info!("Database {} not found, creating it", db);
+            create_database(app_state, &db)
+                .await
+                .map_err(|e| WriteError::Request(format!("Failed to create database {}: {}", db, e)))?;
+            write_lines_with_retry(app_state, base_url, body, precision, deadline).await
+        }
+        result => result,
+    }
+}
+
+// Build the statement creating the target database if it doesn't exist
+fn build_create_database_sql(db: &str) -> String {
+    format!("CREATE DATABASE IF NOT EXISTS `{}`", db.replace('`', "``"))
+}
+
+async fn create_database(
+    app_state: &AppState,
+    db: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Run against the default database, since the target doesn't exist yet
+    run_sql_in(app_state, None, &build_create_database_sql(db)).await?;
+    Ok(())
+}
+
+// Read at most `limit` bytes of an error response body, decoding invalid UTF-8 lossily.
+// Stops pulling chunks once the limit is reached so a huge body never lands in memory.
+async fn read_error_body(mut response: reqwest::Response, limit: usize) -> String {
+    let mut body = Vec::new();
+    let mut truncated = false;
+
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                let remaining = limit - body.len();
+                if chunk.len() > remaining {
+                    body.extend_from_slice(&chunk[..remaining]);
+                    truncated = true;
+                    break;
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Failed to read GreptimeDB error body: {}", e);
+                break;
+            }
+        }
+    }
+
+    let mut text = String::from_utf8_lossy(&body).into_owned();
+    if truncated {
+        text.push_str("... (truncated)");
+    }
+    text
+}
+
+// Recognize GreptimeDB's "database not found" errors, which are otherwise cryptic to clients
+fn is_database_not_found(body: &str) -> bool {
+    let body = body.to_ascii_lowercase();
+    body.contains("database not found") || body.contains("unknown database")
+}
+
+// GreptimeDB's error for a statement naming a table that doesn't exist
+fn is_table_not_found(body: &str) -> bool {
+    body.to_ascii_lowercase().contains("table not found")
+}
+
+#[derive(Debug, Clone)]
+enum WriteError {
+    // Connection-level failure (refused, reset, timed out)
+    Request(String),
+    // GreptimeDB answered with a non-success status
+    Status { status: u16, body: String },
+    // The target database doesn't exist
+    DatabaseNotFound { db: String },
+    // Nothing in the batch could be converted for the configured backend; not sent
+    Unconvertible(String),
+}
+
+impl WriteError {
+    // Network errors and 5xx/429 responses are worth retrying; other 4xx are not
+    fn is_retryable(&self) -> bool {
+        match self {
+            WriteError::Request(_) => true,
+            WriteError::Status { status, .. } => *status >= 500 || *status == 429,
+            WriteError::DatabaseNotFound { .. } | WriteError::Unconvertible(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteError::Request(e) => write!(f, "{}", e),
+            WriteError::Status { body, .. } => write!(f, "GreptimeDB error: {}", body),
+            WriteError::DatabaseNotFound { db } => write!(f, "Database '{}' does not exist in GreptimeDB", db),
+            WriteError::Unconvertible(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+impl WriteError {
+    // The error as logged: GreptimeDB's response body can echo the submitted lines, so it is
+    // masked along with the values when REDACT_VALUES is set
+    fn for_log(&self) -> String {
+        match self {
+            WriteError::Status { body, .. } => format!("GreptimeDB error: {}", Redact(body)),
+            e => e.to_string(),
+        }
+    }
+}
+
+// Full-jitter exponential backoff: a random delay in [0, min(max, base * 2^attempt)].
+// `jitter` yields a value in [0, 1); it is a parameter so the randomness can be pinned.
+fn backoff_delay(attempt: u32, base: Duration, max: Duration, jitter: impl FnOnce() -> f64) -> Duration {
+    let ceiling = base
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(max);
+    ceiling.mul_f64(jitter().clamp(0.0, 1.0))
+}
+
+// Write a body, retrying retryable failures with jittered backoff until the retries or the
+// deadline run out
+async fn write_lines_with_retry(
+    app_state: &AppState,
+    base_url: &str,
+    body: String,
+    precision: Precision,
+    deadline: Option<Instant>,
+) -> Result<(), WriteError> {
+    let config = &app_state.config();
+    let mut attempt = 0;
+
+    loop {
+        match write_lines(app_state, base_url, body.clone(), precision).await {
+            Ok(()) => return Ok(()),
+            Err(e) if e.is_retryable() && attempt < config.write_max_retries => {
+                let delay = backoff_delay(attempt, config.retry_base_delay, config.retry_max_delay, fastrand::f64);
+                if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+                    warn!("Write to {} failed ({}), write deadline reached after {} retries", base_url, e, attempt);
+                    return Err(e);
+                }
+                attempt += 1;
+                warn!(
+                    "Write to {} failed ({}), retry {}/{} in {:?}",
+                    base_url, e, attempt, config.write_max_retries, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+const WRITE_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+// Percent-encode a query parameter value; only RFC 3986 unreserved characters pass through,
+// so `-` in `catalog-schema` stays as is
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// InfluxDB line-protocol write endpoint of a GreptimeDB instance
+fn write_url(config: &Config, base_url: &str, precision: Precision) -> String {
+    format!(
+        "{}/v1/influxdb/api/v2/write?db={}&precision={}",
+        base_url,
+        encode_query_value(&config.greptime_db),
+        precision.as_str()
+    )
+}
+
+// The write request that would be sent, reported by `dry_run=true`
+#[derive(Debug, Serialize)]
+struct WritePreview {
+    // APPEND and the file path for the file sink
+    method: String,
+    url: String,
+    // Secrets are never included
+    headers: std::collections::BTreeMap<&'static str, String>,
+    line_count: usize,
+    body_bytes: usize,
+    body_preview: String,
+}
+
+const BODY_PREVIEW_CHARS: usize = 2000;
+
+// Mirrors build_writer: the file sink, the OpenTSDB backend, or InfluxDB line protocol
+fn preview_write_request(config: &Config, lines: &[String], precision: Precision) -> WritePreview {
+    let (method, url, content_type, body) = if config.write_sink == "file" {
+        ("APPEND".to_string(), config.sink_path.clone(), None, line_protocol_body(lines, true))
+    } else if config.write_backend == "opentsdb" {
+        let points: Vec<serde_json::Value> = lines
+            .iter()
+            .flat_map(|line| opentsdb_data_points(line, precision))
+            .collect();
+        ("POST".to_string(), opentsdb_url(config), Some("application/json"), serde_json::Value::from(points).to_string())
+    } else {
+        (
+            config.write_method.to_string(),
+            write_url(config, &config.greptime_url, precision),
+            Some(WRITE_CONTENT_TYPE),
+            line_protocol_body(lines, config.trailing_newline),
+        )
+    };
+    let mut body_preview: String = body.chars().take(BODY_PREVIEW_CHARS).collect();
+    if body_preview.len() < body.len() {
+        body_preview.push_str("...");
+    }
+
+    WritePreview {
+        method,
+        url,
+        headers: content_type.map(|content_type| ("Content-Type", content_type.to_string())).into_iter().collect(),
+        line_count: lines.len(),
+        body_bytes: body.len(),
+        body_preview,
+    }
+}
+
+// Write a line-protocol body to a single GreptimeDB instance
+async fn write_lines(
+    app_state: &AppState,
+    base_url: &str,
+    body: String,
+    precision: Precision,
+) -> Result<(), WriteError> {
+    let url = write_url(&app_state.config(), base_url, precision);
+
+    info!("Sending to GreptimeDB: {}", url);
+
+    let started = Instant::now();
+    let response = app_state
+        .greptime_request(app_state.config().write_method.clone(), &url)
+        .header("Content-Type", WRITE_CONTENT_TYPE)
+        .body(body)
+        .send()
+        .await;
+    app_state.metrics.greptime_write_seconds.observe(started.elapsed().as_secs_f64());
+    check_write_response(app_state, response).await
+}
+
+// Map a GreptimeDB write response (or the failure to get one) to a WriteError
+async fn check_write_response(
+    app_state: &AppState,
+    response: reqwest::Result<reqwest::Response>,
+) -> Result<(), WriteError> {
+    let response = response.map_err(|e| WriteError::Request(e.to_string()))?;
+
+    if !response.status().is_success() {
+        let status = response.status().as_u16();
+        let error_text = read_error_body(response, app_state.config().error_body_limit).await;
+        if is_database_not_found(&error_text) {
+            return Err(WriteError::DatabaseNotFound { db: app_state.config().greptime_db.clone() });
+        }
+        return Err(WriteError::Status { status, body: error_text });
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+enum EnqueueError {
+    // The queue stayed full for the whole enqueue timeout
+    QueueFull,
+    // The flusher is gone or dropped the job without answering
+    FlusherUnavailable,
+    Write(WriteError),
+}
+
+impl std::fmt::Display for EnqueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnqueueError::QueueFull => write!(f, "Write queue is full"),
+            EnqueueError::FlusherUnavailable => write!(f, "Write queue is unavailable"),
+            EnqueueError::Write(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl EnqueueError {
+    // The error as logged, see WriteError::for_log
+    fn for_log(&self) -> String {
+        match self {
+            EnqueueError::Write(e) => e.for_log(),
+            e => e.to_string(),
+        }
+    }
+
+    fn into_http_error(self) -> ApiError {
+        match self {
+            EnqueueError::QueueFull => {
+                warn!("Write queue full, rejecting request");
+                (StatusCode::SERVICE_UNAVAILABLE, "Write queue is full, retry later".to_string()).into()
+            }
+            EnqueueError::FlusherUnavailable => {
+                error!("Flusher unavailable");
+                (StatusCode::SERVICE_UNAVAILABLE, "Write queue is unavailable".to_string()).into()
+            }
+            EnqueueError::Write(WriteError::DatabaseNotFound { db }) => {
+                warn!("GreptimeDB database {} not found", db);
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "GreptimeDB database '{}' does not exist; create it or set AUTO_CREATE_DB=true",
+                        db
+                    ),
+                )
+                    .into()
+            }
+            EnqueueError::Write(WriteError::Unconvertible(e)) => {
+                warn!("Write not sent: {}", e);
+                (StatusCode::BAD_REQUEST, e).into()
+            }
+            EnqueueError::Write(WriteError::Status { status, body }) => {
+                error!("GreptimeDB rejected the write with {}: {}", status, Redact(&body));
+                // Pass GreptimeDB's status through so clients can tell 4xx from 5xx problems
+                let code = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+                ApiError::Greptime(code, GreptimeErrorResponse {
+                    success: false,
+                    message: format!("GreptimeDB rejected the write with status {}", status),
+                    greptime_status: Some(status),
+                    greptime_error: body,
+                })
+            }
+            EnqueueError::Write(e) => {
+                error!("Failed to send to GreptimeDB: {}", e.for_log());
+                // No response from GreptimeDB at all
+                ApiError::Greptime(StatusCode::BAD_GATEWAY, GreptimeErrorResponse {
+                    success: false,
+                    message: "Failed to reach GreptimeDB".to_string(),
+                    greptime_status: None,
+                    greptime_error: e.to_string(),
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GreptimeErrorResponse {
+    success: bool,
+    message: String,
+    // HTTP status returned by GreptimeDB, absent when it couldn't be reached
+    greptime_status: Option<u16>,
+    greptime_error: String,
+}
+
+// Handler error: a plain-text message, or a structured JSON body for GreptimeDB failures
+#[derive(Debug)]
+enum ApiError {
+    Text(StatusCode, String),
+    Greptime(StatusCode, GreptimeErrorResponse),
+}
+
+impl From<(StatusCode, String)> for ApiError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        ApiError::Text(status, message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::Text(status, message) => (status, message).into_response(),
+            ApiError::Greptime(status, body) => (status, ResponseJson(body)).into_response(),
+        }
+    }
+}
+
+// Queue lines for the flusher and wait for the write result.
+// Waits for queue capacity (backpressure) instead of dropping or growing unbounded.
+async fn enqueue_write(app_state: &AppState, lines: Vec<String>, precision: Precision) -> Result<Duration, EnqueueError> {
+    let (respond_to, response) = oneshot::channel();
+    let job = WriteJob { lines, precision, respond_to };
+
+    match tokio::time::timeout(app_state.config().enqueue_timeout, app_state.write_tx.send(job)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(_)) => return Err(EnqueueError::FlusherUnavailable),
+        Err(_) => return Err(EnqueueError::QueueFull),
+    }
+
+    match response.await {
+        Ok(result) => result.map_err(EnqueueError::Write),
+        Err(_) => Err(EnqueueError::FlusherUnavailable),
+    }
+}
+
+// Background flusher: combines queued jobs into a single GreptimeDB write.
+// Once `shutdown` fires the queue is closed and whatever is left in it is flushed.
+async fn run_flusher(
+    app_state: AppState,
+    mut write_rx: mpsc::Receiver<WriteJob>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut draining = false;
+
+    loop {
+        let first = tokio::select! {
+            job = write_rx.recv() => match job {
+                Some(job) => job,
+                None => break,
+            },
+            _ = &mut shutdown, if !draining => {
+                draining = true;
+                // Further sends fail; recv() returns None once the backlog is empty
+                write_rx.close();
+                info!("Shutting down, draining write queue");
+                continue;
+            }
+        };
+
+        let config = app_state.config();
+        let deadline = tokio::time::Instant::now() + config.flush_max_wait;
+        let mut line_count = first.lines.len();
+        let mut jobs = vec![first];
+
+        // Pick up whatever else is already queued, up to the batch limit
+        while line_count < config.flush_max_lines {
+            match write_rx.try_recv() {
+                Ok(job) => {
+                    line_count += job.lines.len();
+                    jobs.push(job);
+                }
+                Err(_) => break,
+            }
+        }
+
+        // Below FLUSH_MIN_RECORDS, wait for more until the FLUSH_MAX_WAIT_MS deadline;
+        // a shutdown flushes right away
+        while !draining && line_count < config.flush_min_records.min(config.flush_max_lines) {
+            tokio::select! {
+                job = tokio::time::timeout_at(deadline, write_rx.recv()) => match job {
+                    Ok(Some(job)) => {
+                        line_count += job.lines.len();
+                        jobs.push(job);
+                    }
+                    Ok(None) | Err(_) => break,
+                },
+                _ = &mut shutdown => {
+                    draining = true;
+                    write_rx.close();
+                    info!("Shutting down, draining write queue");
+                }
+            }
+        }
+
+        // One write per precision, in the order each precision was first seen
+        let mut batches: Vec<(Precision, Vec<String>, Vec<_>)> = Vec::new();
+        for job in jobs {
+            match batches.iter_mut().find(|(precision, ..)| *precision == job.precision) {
+                Some((_, lines, waiters)) => {
+                    lines.extend(job.lines);
+                    waiters.push(job.respond_to);
+                }
+                None => batches.push((job.precision, job.lines, vec![job.respond_to])),
+            }
+        }
+
+        for (precision, lines, waiters) in batches {
+            info!(
+                "Flushing {} lines from {} queued requests (precision {})",
+                lines.len(),
+                waiters.len(),
+                precision.as_str()
+            );
+
+            let started = Instant::now();
+            let result = app_state.writer.write(&app_state, lines, precision).await;
+
+            // A dead-lettered batch is safe, but GreptimeDB is still failing: readiness stays as it was
+            match &result {
+                Ok(Delivery::Primary) => {
+                    if !app_state.ready.swap(true, Ordering::Relaxed) {
+                        info!("First write succeeded, reporting ready");
+                    }
+                    app_state.record_write_result(None);
+                }
+                Ok(Delivery::Fallback { error }) => {
+                    app_state.record_write_result(Some(format!("{} (batch written to fallback file)", error)));
+                }
+                Err(e) => app_state.record_write_result(Some(e.to_string())),
+            }
+            let result = result.map(|_| started.elapsed());
+
+            for waiter in waiters {
+                // The handler may have gone away; nothing to do then
+                let _ = waiter.send(result.clone());
+            }
+        }
+    }
+}
+
+// Run a SQL statement through GreptimeDB's SQL HTTP API and return the JSON result
+async fn run_sql(
+    app_state: &AppState,
+    sql: &str,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    run_sql_in(app_state, Some(&app_state.config().greptime_db), sql).await
+}
+
+// Run a SQL statement against `db`, or GreptimeDB's default database when None
+async fn run_sql_in(
+    app_state: &AppState,
+    db: Option<&str>,
+    sql: &str,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    let url = match db {
+        Some(db) => format!("{}/v1/sql?db={}", app_state.config().greptime_url, encode_query_value(db)),
+        None => format!("{}/v1/sql", app_state.config().greptime_url),
+    };
+
+    info!("Running SQL on GreptimeDB: {}", sql);
+
+    let response = app_state
+        .greptime_request(reqwest::Method::POST, &url)
+        .form(&[("sql", sql)])
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let error_text = read_error_body(response, app_state.config().error_body_limit).await;
+        return Err(format!("GreptimeDB error: {}", error_text).into());
+    }
+
+    Ok(response.json().await?)
+}
+
+// Quote a string literal for GreptimeDB SQL
+fn sql_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+// Quote a table name for GreptimeDB SQL
+fn sql_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+// Build the DELETE statement removing a device's data in [start, end) from `table`
+fn build_delete_sql(table: &str, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+    format!(
+        "DELETE FROM {} WHERE device_id = {} AND greptime_timestamp >= {} AND greptime_timestamp < {}",
+        sql_identifier(table),
+        sql_quote(device_id),
+        sql_quote(&start.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+        sql_quote(&end.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+    )
+}
+
+const DAILY_MEASUREMENT: &str = "heart_rate_daily";
+
+// Per-device min/max/avg/count of the shared heart_rate measurement over [start, end)
+fn build_rollup_sql(start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+    format!(
+        "SELECT device_id, MIN(value), MAX(value), AVG(value), COUNT(value) FROM {} \
+         WHERE greptime_timestamp >= {} AND greptime_timestamp < {} GROUP BY device_id ORDER BY device_id",
+        DEFAULT_MEASUREMENT,
+        sql_quote(&start.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+        sql_quote(&end.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+    )
+}
+
+// heart_rate_daily lines from build_rollup_sql rows, timestamped at the start of the day
+fn rollup_lines(rows: &[Vec<serde_json::Value>], day_start: DateTime<Utc>, precision: Precision) -> Vec<String> {
+    rows.iter()
+        .filter_map(|row| {
+            let [device_id, min, max, avg, count] = &row[..] else {
+                return None;
+            };
+            Some(format!(
+                "{},device_id={} min={},max={},avg={},samples={}i {}",
+                DAILY_MEASUREMENT,
+                escape_tag(device_id.as_str()?),
+                min.as_f64()?,
+                max.as_f64()?,
+                avg.as_f64()?,
+                count.as_u64()?,
+                precision.timestamp(&day_start)
+            ))
+        })
+        .collect()
+}
+
+// Summarize one UTC day into heart_rate_daily; returns how many devices had data
+async fn rollup_day(app_state: &AppState, day: chrono::NaiveDate) -> Result<usize, String> {
+    let start = Utc.from_utc_datetime(&day.and_time(chrono::NaiveTime::MIN));
+    let end = start + chrono::Duration::days(1);
+
+    let result = run_sql(app_state, &build_rollup_sql(start, end))
+        .await
+        .map_err(|e| format!("rollup query failed: {}", e))?;
+    let precision = app_state.config().precision;
+    let lines = rollup_lines(&sql_rows(&result), start, precision);
+    if lines.is_empty() {
+        return Ok(0);
+    }
+
+    let device_count = lines.len();
+    enqueue_write(app_state, lines, precision)
+        .await
+        .map_err(|e| format!("rollup write failed: {}", e))?;
+    Ok(device_count)
+}
+
+// Next time-of-day `at` (UTC) strictly after `now`
+fn next_rollup_time(now: DateTime<Utc>, at: chrono::NaiveTime) -> DateTime<Utc> {
+    let today = Utc.from_utc_datetime(&now.date_naive().and_time(at));
+    if today > now {
+        today
+    } else {
+        today + chrono::Duration::days(1)
+    }
+}
+
+// Summarize the day before `today` plus any earlier day whose rollup failed, then apply
+// RAW_RETENTION_DAYS. Raw rows are only deleted once every pending day before the cutoff has
+// been summarized, so a failed rollup postpones retention instead of losing that day.
+async fn rollup_and_expire(
+    app_state: &AppState,
+    today: chrono::NaiveDate,
+    pending_days: &mut std::collections::BTreeSet<chrono::NaiveDate>,
+) {
+    pending_days.extend(today.pred_opt());
+    for day in pending_days.clone() {
+        match rollup_day(app_state, day).await {
+            Ok(device_count) => {
+                info!("Daily rollup for {}: {} devices", day, device_count);
+                pending_days.remove(&day);
+            }
+            Err(e) => error!("Daily rollup for {} failed, retrying at the next run: {}", day, e),
+        }
+    }
+
+    let Some(days) = app_state.config().raw_retention_days else {
+        return;
+    };
+    let cutoff = Utc.from_utc_datetime(&today.and_time(chrono::NaiveTime::MIN)) - chrono::Duration::days(days.into());
+    if let Some(day) = pending_days.first().filter(|day| **day < cutoff.date_naive()) {
+        warn!("Postponing raw retention: the rollup for {} has not succeeded yet", day);
+        return;
+    }
+
+    let sql = format!(
+        "DELETE FROM {} WHERE greptime_timestamp < {}",
+        DEFAULT_MEASUREMENT,
+        sql_quote(&cutoff.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+    );
+    match run_sql(app_state, &sql).await {
+        Ok(result) => info!(
+            "Deleted {} raw rows older than {}",
+            result["output"][0]["affectedrows"].as_u64().unwrap_or(0),
+            cutoff
+        ),
+        Err(e) => error!("Raw retention delete failed: {}", e),
+    }
+}
+
+// Daily at ROLLUP_AT: summarize the previous UTC day, then apply RAW_RETENTION_DAYS.
+// Only the shared heart_rate measurement is covered, not per-device measurements.
+async fn run_daily_rollup(app_state: AppState, at: chrono::NaiveTime) {
+    // Days whose rollup failed; retried at every run until they succeed
+    let mut pending_days = std::collections::BTreeSet::new();
+    loop {
+        let now = Utc::now();
+        let next = next_rollup_time(now, at);
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+
+        rollup_and_expire(&app_state, next.date_naive(), &mut pending_days).await;
+    }
+}
+
+#[derive(Deserialize)]
+struct DevicesParams {
+    // Measurement to list devices of (default heart_rate), including its per-device tables
+    measurement: Option<String>,
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DevicesResponse {
+    success: bool,
+    devices: Vec<String>,
+}
+
+// Extract the rows of the first result set from a GreptimeDB SQL response:
+// {"output":[{"records":{"schema":...,"rows":[[...], ...]}}]}
+fn sql_rows(result: &serde_json::Value) -> Vec<Vec<serde_json::Value>> {
+    result["output"][0]["records"]["rows"]
+        .as_array()
+        .map(|rows| {
+            rows.iter()
+                .filter_map(|row| row.as_array().cloned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[derive(Deserialize)]
+struct TzTestParams {
+    datetime: String,
+    // Named timezone for naive local timestamps; defaults to LOCAL_TZ
+    tz: Option<String>,
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct TzTestResponse {
+    input: String,
+    // chinese or rfc3339
+    format: &'static str,
+    timezone: String,
+    utc: String,
+    // The time with the offset it was resolved in
+    local: String,
+    offset_minutes: i32,
+    // exact, ambiguous or nonexistent (see resolve_local_time)
+    resolution: &'static str,
+}
+
+// Run a single timestamp through the parser to check timezone handling without writing anything
+async fn tz_test(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    Query(params): Query<TzTestParams>,
+    headers: HeaderMap,
+) -> Result<ResponseJson<TzTestResponse>, (StatusCode, String)> {
+
+    authorize(&app_state.config(), &headers, params.token.as_deref())?;
+
+    let tz = match params.tz.as_deref().map(str::trim).filter(|tz| !tz.is_empty()) {
+        Some(name) => Some(name.parse::<chrono_tz::Tz>().map_err(|_| {
+            (StatusCode::BAD_REQUEST, format!("Unknown timezone '{}'", name))
+        })?),
+        None => app_state.config().local_tz,
+    };
+
+    let input = params.datetime.trim();
+    let (time, resolution, format) = parse_any_timestamp(input, tz, &app_state.config().timestamp_formats).ok_or_else(|| {
+        (StatusCode::BAD_REQUEST, format!("Could not parse '{}' as a timestamp", input))
+    })?;
+
+    Ok(ResponseJson(TzTestResponse {
+        input: input.to_string(),
+        format: format.as_str(),
+        // RFC 3339 input carries its own offset, so the timezone only applies to local formats
+        timezone: match (format, tz) {
+            (TimestampFormat::Rfc3339, _) => "from input".to_string(),
+            (_, Some(tz)) => tz.to_string(),
+            (_, None) => "UTC+08:00".to_string(),
+        },
+        utc: time.with_timezone(&Utc).to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+        local: time.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+        offset_minutes: time.offset().local_minus_utc() / 60,
+        resolution: match resolution {
+            LocalResolution::Exact => "exact",
+            LocalResolution::Ambiguous => "ambiguous",
+            LocalResolution::Nonexistent => "nonexistent",
+        },
+    }))
+}
+
+// Whether `table` holds device readings for `measurement`: the shared table itself or one of its
+// per-device tables (<measurement>_<device>), but not the daily rollup
+fn is_device_table(table: &str, measurement: &str) -> bool {
+    table != DAILY_MEASUREMENT
+        && table
+            .strip_prefix(measurement)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('_'))
+}
+
+// List the device_ids that have data
+async fn list_devices(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    Query(params): Query<DevicesParams>,
+    headers: HeaderMap,
+) -> Result<ResponseJson<DevicesResponse>, (StatusCode, String)> {
+
+    authorize(&app_state.config(), &headers, params.token.as_deref())?;
+    let measurement = params.measurement.as_deref().unwrap_or(DEFAULT_MEASUREMENT);
+    check_measurement(&app_state.config(), measurement)?;
+
+    let sql_error = |e: Box<dyn std::error::Error + Send + Sync>| {
+        error!("Failed to list devices: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("GreptimeDB error: {}", e))
+    };
+
+    // The shared table and any per-device tables written with MEASUREMENT_PER_DEVICE
+    let result = run_sql(&app_state, &format!("SHOW TABLES LIKE {}", sql_quote(&format!("{}%", measurement))))
+        .await
+        .map_err(sql_error)?;
+    let tables: Vec<String> = sql_rows(&result)
+        .iter()
+        .filter_map(|row| row.first()?.as_str().map(str::to_string))
+        .filter(|table| is_device_table(table, measurement))
+        .collect();
+
+    let mut devices = std::collections::BTreeSet::new();
+    for table in tables {
+        let sql = format!("SELECT DISTINCT device_id FROM {}", sql_identifier(&table));
+        let result = run_sql(&app_state, &sql).await.map_err(sql_error)?;
+        devices.extend(
+            sql_rows(&result)
+                .iter()
+                .filter_map(|row| row.first()?.as_str().map(str::to_string)),
+        );
+    }
+
+    Ok(ResponseJson(DevicesResponse { success: true, devices: devices.into_iter().collect() }))
+}
+
+// Delete a device's data over a time range (e.g. for GDPR erasure requests)
+async fn delete_device_data(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    Path(device_id): Path<String>,
+    Query(params): Query<DeleteParams>,
+    headers: HeaderMap,
+) -> Result<ResponseJson<DeleteResponse>, (StatusCode, String)> {
+
+    authorize_destructive(&app_state.config(), &headers, params.token.as_deref())?;
+    // Match how the device id was stored
+    let device_id = normalize_device_id(&app_state.config(), &device_id)?;
+
+    let parse_time = |name: &str, value: &str| {
+        DateTime::parse_from_rfc3339(value)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid {} time '{}': {}", name, value, e)))
+    };
+    let start = parse_time("start", &params.start)?;
+    let end = parse_time("end", &params.end)?;
+
+    if start >= end {
+        return Err((StatusCode::BAD_REQUEST, "start must be before end".to_string()));
+    }
+
+    let measurement = params.measurement.as_deref().unwrap_or(DEFAULT_MEASUREMENT);
+    check_measurement(&app_state.config(), measurement)?;
+
+    // The device's rows are in the shared table or, with MEASUREMENT_PER_DEVICE, its own table;
+    // usually only one of them exists
+    let mut deleted_count = 0;
+    for table in [measurement.to_string(), per_device_measurement(measurement, &device_id)] {
+        let sql = build_delete_sql(&table, &device_id, start, end);
+        match run_sql(&app_state, &sql).await {
+            Ok(result) => deleted_count += result["output"][0]["affectedrows"].as_u64().unwrap_or(0),
+            Err(e) if is_table_not_found(&e.to_string()) => debug!("Table {} does not exist, nothing to delete", table),
+            Err(e) => {
+                error!("Failed to delete device data: {}", e);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("GreptimeDB error: {}", e)));
+            }
+        }
+    }
+
+    info!("Deleted {} rows for device {}", deleted_count, device_id);
+
+    Ok(ResponseJson(DeleteResponse {
+        success: true,
+        message: format!("Deleted {} rows for device {}", deleted_count, device_id),
+        deleted_count,
+    }))
+}
+
+struct MultipartUpload {
+    file: Bytes,
+    device_id: Option<String>,
+}
+
+// Pull the export file and an optional `device_id` field out of a multipart body.
+// The file is the part named `file`, or failing that the first part with a filename.
+async fn extract_multipart_upload(
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<MultipartUpload, (StatusCode, String)> {
+    let mut request = Request::new(Body::from(body));
+    *request.headers_mut() = headers.clone();
+
+    let mut multipart = Multipart::from_request(request, &())
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid multipart body: {}", e)))?;
+
+    let mut file = None;
+    let mut device_id = None;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid multipart body: {}", e)))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        let is_file = name == "file" || field.file_name().is_some();
+
+        if name == "device_id" {
+            let value = field
+                .text()
+                .await
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid device_id field: {}", e)))?;
+            device_id = Some(value.trim().to_string()).filter(|value| !value.is_empty());
+        } else if is_file && (file.is_none() || name == "file") {
+            let bytes = field
+                .bytes()
+                .await
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid file field: {}", e)))?;
+            file = Some(bytes);
+        }
+    }
+
+    let file = file.ok_or((StatusCode::BAD_REQUEST, "Multipart body has no file part".to_string()))?;
+
+    Ok(MultipartUpload { file, device_id })
+}
+
+#[derive(Deserialize)]
+struct RawWriteParams {
+    // When set, injected as a device_id tag into every line
+    device_id: Option<String>,
+    // Precision of the timestamps in the body (s, ms, us, ns); defaults to WRITE_PRECISION
+    precision: Option<String>,
+    token: Option<String>,
+}
+
+// Split a raw line-protocol body into lines, tolerating CRLF, blank lines and a trailing newline
+fn split_raw_lines(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+// Add a tag to a line-protocol line: inserted after the measurement and existing tags,
+// i.e. before the first unescaped space
+fn inject_tag(line: &str, key: &str, value: &str) -> String {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            ' ' if !escaped => {
+                return format!("{},{}={}{}", &line[..i], key, escape_tag(value), &line[i..]);
+            }
+            _ => escaped = false,
+        }
+    }
+    line.to_string()
+}
+
+// Pass pre-formatted line protocol through to GreptimeDB untouched
+async fn write_raw_line_protocol(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    Query(params): Query<RawWriteParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<ResponseJson<ApiResponse>, ApiError> {
+
+    authorize(&app_state.config(), &headers, params.token.as_deref())?;
+
+    let text = String::from_utf8(body.to_vec())
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid UTF-8: {}", e)))?;
+
+    let mut lines = split_raw_lines(&text);
+    if lines.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "No line protocol lines found".to_string()).into());
+    }
+
+    if let Some(device_id) = &params.device_id {
+        let device_id = normalize_device_id(&app_state.config(), device_id)?;
+        lines = lines
+            .iter()
+            .map(|line| inject_tag(line, "device_id", &device_id))
+            .collect();
+    }
+
+    info!("Forwarding {} raw line protocol lines", lines.len());
+
+    let precision = request_precision(&app_state.config(), params.precision.as_deref())?;
+    let processed_count = lines.len();
+    enqueue_write(&app_state, lines, precision)
+        .await
+        .map_err(EnqueueError::into_http_error)?;
+
+    Ok(ResponseJson(ApiResponse {
+        success: true,
+        message: format!("Successfully forwarded {} lines", processed_count),
+        processed_count,
+        ..Default::default()
+    }))
+}
+
+// Count records whose timestamps the write precision will truncate, with a warning when any are
+fn count_truncated(records: &[HeartRateRecord], precision: Precision, warnings: &mut Vec<String>) -> usize {
+    let truncated = records.iter().filter(|record| precision.truncates(&record.timestamp)).count();
+    if truncated > 0 {
+        let warning = format!(
+            "{} timestamps were truncated to write precision {}",
+            truncated,
+            precision.as_str()
+        );
+        warn!("{}", warning);
+        warnings.push(warning);
+    }
+    truncated
+}
+
+// Warnings reporting local times adjusted under the DST policy of resolve_local_time
+fn local_time_warnings(ambiguous_count: usize, nonexistent_count: usize) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if ambiguous_count > 0 {
+        warnings.push(format!("{} ambiguous local times resolved to the earlier offset", ambiguous_count));
+    }
+    if nonexistent_count > 0 {
+        warnings.push(format!("{} nonexistent local times shifted past the DST gap", nonexistent_count));
+    }
+    warnings
+}
+
+// Result of writing a request one day at a time
+struct SplitWriteOutcome {
+    days: Vec<DayResult>,
+    // Records in days that were written
+    processed_count: usize,
+    write_time: Duration,
+    first_error: Option<EnqueueError>,
+}
+
+// Write `lines` as one batch per UTC day (`line_days[i]` is the day of `lines[i]`), in date
+// order, continuing past failed days. An audit line rides along with the last day's batch.
+async fn write_by_day(
+    app_state: &AppState,
+    line_days: Vec<chrono::NaiveDate>,
+    lines: Vec<String>,
+    mut audit_line: Option<String>,
+    precision: Precision,
+) -> SplitWriteOutcome {
+    let mut batches: std::collections::BTreeMap<chrono::NaiveDate, Vec<String>> = std::collections::BTreeMap::new();
+    for (day, line) in line_days.into_iter().zip(lines) {
+        batches.entry(day).or_default().push(line);
+    }
+
+    let mut outcome = SplitWriteOutcome {
+        days: Vec::new(),
+        processed_count: 0,
+        write_time: Duration::ZERO,
+        first_error: None,
+    };
+
+    let day_count = batches.len();
+    for (i, (day, mut batch)) in batches.into_iter().enumerate() {
+        let record_count = batch.len();
+        if i + 1 == day_count {
+            batch.extend(audit_line.take());
+        }
+
+        info!("Writing {} records for {}", record_count, day);
+        let result = enqueue_write(app_state, batch, precision).await;
+        let error = match result {
+            Ok(write_time) => {
+                outcome.processed_count += record_count;
+                outcome.write_time += write_time;
+                None
+            }
+            Err(e) => {
+                error!("Write for {} failed: {}", day, e.for_log());
+                let message = e.to_string();
+                outcome.first_error.get_or_insert(e);
+                Some(message)
+            }
+        };
+
+        outcome.days.push(DayResult {
+            date: day.to_string(),
+            processed_count: record_count,
+            success: error.is_none(),
+            error,
+        });
+    }
+
+    outcome
+}
+
+// Explicit device_id (query or form field), else one named in the body, else the default
+fn resolve_device_id(
+    config: &Config,
+    explicit: Option<String>,
+    from_body: Option<String>,
+) -> Result<String, (StatusCode, String)> {
+    if explicit.is_none() {
+        if let Some(device_id) = &from_body {
+            info!("Device ID from body: {}", device_id);
+        }
+    }
+    let device_id = explicit.or(from_body);
+    normalize_device_id(config, device_id.as_deref().unwrap_or(DEFAULT_DEVICE_ID))
+}
+
+// Reject records from before MIN_TS (e.g. a device clock reset to 1970).
+// Returns the remaining records and how many were rejected.
+fn reject_before_min_timestamp(
+    records: Vec<HeartRateRecord>,
+    min_timestamp: DateTime<Utc>,
+    warnings: &mut Vec<String>,
+) -> (Vec<HeartRateRecord>, usize) {
+    let before = records.len();
+    let records: Vec<HeartRateRecord> = records
+        .into_iter()
+        .filter(|record| {
+            if record.timestamp < min_timestamp {
+                let warning = format!(
+                    "Rejected record at {}: timestamp is before minimum {}",
+                    Redact(record.timestamp),
+                    min_timestamp
+                );
+                warn!("{}", warning);
+                warnings.push(warning);
+                false
+            } else {
+                true
+            }
+        })
+        .collect();
+    let rejected = before - records.len();
+    (records, rejected)
+}
+
+// Drop samples that change faster than `max_rate` BPM per second from both neighbours (the
+// previous kept sample and the next one), so a step change is kept while a lone spike goes;
+// the last sample only has the previous one to go by, and the first counts as a spike when
+// it jumps to a next sample that fits the one after it. Gaps under a second count as one
+// second. Returns the rest and how many were dropped.
+fn drop_implausible_jumps(
+    records: Vec<HeartRateRecord>,
+    max_rate: Option<f64>,
+    warnings: &mut Vec<String>,
+) -> (Vec<HeartRateRecord>, usize) {
+    let max_rate = match max_rate {
+        Some(max_rate) => max_rate,
+        None => return (records, 0),
+    };
+
+    // The change and seconds between two samples, when it is too fast
+    let jump = |from: (DateTime<Utc>, f64), to: (DateTime<Utc>, f64)| {
+        let seconds = (to.0 - from.0).num_milliseconds().abs() as f64 / 1000.0;
+        let change = (to.1 - from.1).abs();
+        (change > max_rate * seconds.max(1.0)).then_some((change, seconds))
+    };
+
+    let points: Vec<(DateTime<Utc>, f64)> = records.iter().map(|record| (record.timestamp, record.value)).collect();
+    let mut kept: Vec<HeartRateRecord> = Vec::with_capacity(records.len());
+    let mut previous = None;
+    let mut dropped = 0;
+    for (i, record) in records.into_iter().enumerate() {
+        let point = points[i];
+        let next = points.get(i + 1).copied();
+        let spike = match previous {
+            Some(previous) => jump(previous, point).filter(|_| next.is_none_or(|next| jump(point, next).is_some())),
+            None => match (next, points.get(i + 2).copied()) {
+                (Some(next), Some(after)) if jump(next, after).is_none() => jump(next, point),
+                _ => None,
+            },
+        };
+        if let Some((change, seconds)) = spike {
+            let warning = format!(
+                "Dropped record at {}: jump of {} BPM in {}s exceeds {} BPM/s",
+                Redact(record.timestamp),
+                Redact(change),
+                seconds,
+                max_rate
+            );
+            warn!("{}", warning);
+            warnings.push(warning);
+            dropped += 1;
+            continue;
+        }
+        previous = Some(point);
+        kept.push(record);
+    }
+    (kept, dropped)
+}
+
+// Drop records older than MAX_AGE_DAYS relative to now. Unlike MIN_TS this is a rolling
+// cutoff, and drops are summarized in one warning since a backfill can hit many records.
+fn drop_expired(
+    records: Vec<HeartRateRecord>,
+    max_age: Option<chrono::Duration>,
+    warnings: &mut Vec<String>,
+) -> (Vec<HeartRateRecord>, usize) {
+    let max_age = match max_age {
+        Some(max_age) => max_age,
+        None => return (records, 0),
+    };
+
+    let cutoff = Utc::now() - max_age;
+    let before = records.len();
+    let records: Vec<HeartRateRecord> = records
+        .into_iter()
+        .filter(|record| record.timestamp >= cutoff)
+        .collect();
+    let expired = before - records.len();
+
+    if expired > 0 {
+        let warning = format!(
+            "Dropped {} records older than {} days (before {})",
+            expired,
+            max_age.num_days(),
+            cutoff.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        );
+        warn!("{}", warning);
+        warnings.push(warning);
+    }
+    (records, expired)
+}
+
+// Measurement for a request: shared, or per device when enabled by query param or config.
+// `measurement` replaces heart_rate in either case.
+fn request_measurement(config: &Config, measurement: Option<&str>, per_device: Option<bool>, device_id: &str) -> String {
+    let base = measurement.unwrap_or(DEFAULT_MEASUREMENT);
+    if per_device.unwrap_or(config.measurement_per_device) {
+        per_device_measurement(base, device_id)
+    } else {
+        base.to_string()
+    }
+}
+
+// Tags written on every line of a request: device_id, plus device_name and user_id when known
+fn request_tags(
+    app_state: &AppState,
+    device_id: &str,
+    user_id: Option<&str>,
+    extra_tags: &[(String, String)],
+) -> Vec<(String, String)> {
+    let mut tags = vec![("device_id".to_string(), device_id.to_string())];
+    if let Some(device_name) = app_state.device_name(device_id) {
+        tags.push(("device_name".to_string(), device_name));
+    }
+    if let Some(user_id) = user_id {
+        tags.push(("user_id".to_string(), user_id.to_string()));
+    }
+    tags.extend(extra_tags.iter().cloned());
+    tags
+}
+
+// Tag keys set by the proxy itself, which `tags=` can't override
+const RESERVED_TAG_KEYS: &[&str] = &["device_id", "device_name", "user_id", "zone"];
+
+// Parse `tags=key:value,key:value`; 400 when malformed or over MAX_TAGS
+fn parse_extra_tags(config: &Config, spec: Option<&str>) -> Result<Vec<(String, String)>, (StatusCode, String)> {
+    let Some(spec) = spec else {
+        return Ok(Vec::new());
+    };
+    let invalid = |message: String| (StatusCode::BAD_REQUEST, format!("Invalid tags: {}", message));
+
+    let mut tags: Vec<(String, String)> = Vec::new();
+    for pair in spec.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair
+            .split_once(':')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .ok_or_else(|| invalid(format!("'{}' is not key:value", pair)))?;
+        if key.is_empty() || key.len() > 64 || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(invalid(format!("tag key '{}' must be 1-64 characters of letters, digits and '_'", key)));
+        }
+        if RESERVED_TAG_KEYS.contains(&key) {
+            return Err(invalid(format!("tag key '{}' is reserved", key)));
+        }
+        if value.is_empty() || value.len() > 256 || value.chars().any(char::is_control) {
+            return Err(invalid(format!("value of tag '{}' must be 1-256 printable characters", key)));
+        }
+        if tags.iter().any(|(existing, _)| existing == key) {
+            return Err(invalid(format!("tag key '{}' is given more than once", key)));
+        }
+        tags.push((key.to_string(), value.to_string()));
+    }
+
+    if tags.len() > config.max_tags {
+        return Err(invalid(format!("{} tags given, at most {} are allowed", tags.len(), config.max_tags)));
+    }
+    Ok(tags)
+}
+
+// Decode a request body as text: UTF-16 (LE or BE) when it starts with that BOM, otherwise UTF-8
+fn decode_body(body: &[u8]) -> Result<String, (StatusCode, String)> {
+    match encoding_rs::Encoding::for_bom(body) {
+        Some((encoding, bom_length)) if encoding != encoding_rs::UTF_8 => encoding
+            .decode_without_bom_handling_and_without_replacement(&body[bom_length..])
+            .map(|text| text.into_owned())
+            .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("Invalid {} body", encoding.name()))),
+        _ => String::from_utf8(body.to_vec())
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid UTF-8: {}", e))),
+    }
+}
+
+// Enforce ALLOWED_CONTENT_TYPES with 415; parameters after `;` are ignored and `type/*` matches any subtype
+fn check_content_type(config: &Config, content_type: Option<&str>) -> Result<(), (StatusCode, String)> {
+    if config.allowed_content_types.is_empty() {
+        return Ok(());
+    }
+
+    let media_type = content_type
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let allowed = config.allowed_content_types.iter().any(|allowed| match allowed.strip_suffix("/*") {
+        Some(main_type) => media_type.split('/').next() == Some(main_type),
+        None => *allowed == media_type,
+    });
+
+    if allowed {
+        Ok(())
+    } else {
+        let received = if media_type.is_empty() { "none" } else { media_type.as_str() };
+        Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!(
+                "Content-Type {} is not accepted; allowed: {}",
+                received,
+                config.allowed_content_types.join(", ")
+            ),
+        ))
+    }
+}
+
+// Whether the Content-Type announces a JSON body (application/json or any +json type)
+fn is_json_content_type(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .is_some_and(|media_type| media_type == "application/json" || media_type.ends_with("+json"))
+}
+
+// Reject parameters /heart-rate/stream would otherwise silently ignore, whatever STRICT_QUERY_PARAMS says
+fn reject_stream_params(raw_query: Option<&str>) -> Result<(), (StatusCode, String)> {
+    let unsupported: Vec<&str> = query_keys(raw_query)
+        .into_iter()
+        .filter(|key| STREAM_UNSUPPORTED_PARAM_KEYS.contains(key))
+        .collect();
+
+    if unsupported.is_empty() {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Query parameter(s) not supported on /heart-rate/stream: {}; use /heart-rate instead",
+                unsupported.join(", ")
+            ),
+        ))
+    }
+}
+
+// Reject query parameter combinations whose meaning would be ambiguous.
+// The Content-Type picks the body format; `format` only picks how return_records are rendered.
+fn check_param_conflicts(params: &QueryParams) -> Result<(), (StatusCode, String)> {
+    if let Some(format) = &params.format {
+        if !params.return_records {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "format={} selects the format of returned records and requires return_records=true; \
+                     it does not describe the request body",
+                    format
+                ),
+            ));
+        }
+    }
+
+    if params.disambiguate_dupes && params.sort == Some(false) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "disambiguate_dupes works on sorted records and cannot be combined with sort=false".to_string(),
+        ));
+    }
+
+    if params.dry_run && params.split_by_day {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "dry_run previews a single write and cannot be combined with split_by_day".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Parse options for a request: the configured ones plus the `strategy` override
+fn request_parse_options(config: &Config, params: &QueryParams) -> Result<ParseOptions, (StatusCode, String)> {
+    let mut options = ParseOptions::from_config(config);
+    options.preserve_order = params.sort == Some(false);
+    options.decimal_comma = params.decimal_comma;
+    options.with_source_line = params.with_source_line;
+    if let Some(strategy) = &params.strategy {
+        options.strategy = PairingStrategy::parse(strategy).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Unknown strategy '{}', expected sequential, alternating, values_first, timestamps_first or proportional", strategy),
+            )
+        })?;
+    }
+    options.synthesize_timestamps = request_synthesized_timestamps(params)?;
+    Ok(options)
+}
+
+// `synthesize_ts=true` settings; interval_secs is required and end_time defaults to now
+fn request_synthesized_timestamps(params: &QueryParams) -> Result<Option<SynthesizedTimestamps>, (StatusCode, String)> {
+    if !params.synthesize_ts {
+        if params.interval_secs.is_some() || params.end_time.is_some() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "interval_secs and end_time only apply with synthesize_ts=true".to_string(),
+            ));
+        }
+        return Ok(None);
+    }
+
+    let interval_secs = params.interval_secs.filter(|secs| *secs > 0).ok_or_else(|| {
+        (StatusCode::BAD_REQUEST, "synthesize_ts=true requires a positive interval_secs".to_string())
+    })?;
+    let interval = i64::try_from(interval_secs)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("interval_secs {} is too large", interval_secs)))?;
+
+    let end = match &params.end_time {
+        Some(value) => parse_config_time(value).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid end_time '{}', expected RFC 3339 or YYYY-MM-DD", value),
+            )
+        })?,
+        None => Utc::now(),
+    };
+
+    Ok(Some(SynthesizedTimestamps { interval, end }))
+}
+
+// Write precision for a request: the `precision` query parameter, or WRITE_PRECISION
+fn request_precision(config: &Config, requested: Option<&str>) -> Result<Precision, (StatusCode, String)> {
+    match requested {
+        Some(value) => Precision::parse(value).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Unknown precision '{}', expected s, ms, us or ns", value),
+            )
+        }),
+        None => Ok(config.precision),
+    }
+}
+
+// 429 for a write rejected by check_debounce
+fn debounced_error(device_id: &str, remaining: Duration) -> (StatusCode, String) {
+    warn!("Rejecting repeated write for {} within the debounce window", device_id);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        format!(
+            "A write for device '{}' was just accepted; retry in {}ms if this is not a duplicate",
+            device_id,
+            remaining.as_millis().max(1)
+        ),
+    )
+}
+
+// Message for a successful write of `count` records
+fn success_message(config: &Config, count: usize) -> String {
+    match &config.success_message_template {
+        Some(template) => template.replace("{count}", &count.to_string()),
+        None => format!("Successfully processed {} heart rate records", count),
+    }
+}
+
+// Main processing function
+async fn process_heart_rate_text(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    Query(params): Query<QueryParams>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+
+    reject_unknown_params(&app_state.config(), raw_query.as_deref(), QUERY_PARAM_KEYS)?;
+    authorize(&app_state.config(), &headers, params.token.as_deref())?;
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    check_content_type(&app_state.config(), content_type)?;
+    check_param_conflicts(&params)?;
+    let mut parse_options = request_parse_options(&app_state.config(), &params)?;
+    parse_options.json_input = is_json_content_type(content_type);
+    let precision = request_precision(&app_state.config(), params.precision.as_deref())?;
+
+    // Browser uploads arrive as multipart/form-data with the export as a file part
+    let is_multipart = content_type.is_some_and(|value| value.starts_with("multipart/form-data"));
+
+    let (body, form_device_id) = if is_multipart {
+        let upload = extract_multipart_upload(&headers, body).await?;
+        (upload.file, upload.device_id)
+    } else {
+        (body, None)
+    };
+
+    // Convert bytes to string
+    let text: Arc<str> = decode_body(&body)?.into();
+
+    // The query param wins over the form field, which wins over a device line in the body
+    let explicit_device_id = params.device_id.or(form_device_id);
+
+    // user_id comes from the query or the X-User-Id header, query first
+    let user_id = params.user_id.or_else(|| {
+        headers
+            .get("x-user-id")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string())
+    });
+    if let Some(user_id) = &user_id {
+        validate_user_id(user_id)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid user_id: {}", e)))?;
+    }
+    if let Some(measurement) = &params.measurement {
+        check_measurement(&app_state.config(), measurement)?;
+    }
+    let extra_tags = parse_extra_tags(&app_state.config(), params.tags.as_deref())?;
+
+    let csv_output = match params.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(other) => {
+            return Err((StatusCode::BAD_REQUEST, format!("Unsupported format '{}', expected json or csv", other)).into());
+        }
+    };
+
+    let bucket_width = params.bucket_width.unwrap_or(app_state.config().histogram_bucket_width);
+    if params.histogram && !(bucket_width > 0.0 && bucket_width.is_finite()) {
+        return Err((StatusCode::BAD_REQUEST, "bucket_width must be a positive number".to_string()).into());
+    }
+
+    let outlier_filter = match params.filter.as_deref().map(OutlierFilter::parse) {
+        Some(Ok(filter)) => Some(filter),
+        Some(Err(e)) => return Err((StatusCode::BAD_REQUEST, format!("Invalid filter: {}", e)).into()),
+        None => None,
+    };
+
+    info!("=== Received Heart Rate Data ===");
+    if let Some(device_id) = &explicit_device_id {
+        info!("Device ID: {}", device_id);
+    }
+    if let Some(user_id) = &user_id {
+        info!("User ID: {}", user_id);
+    }
+    info!("Data length: {} characters", text.len());
+    debug!("First 500 bytes of raw data:\n{}", Redact(truncate_at_char_boundary(&text, 500)));
+
+    // Parse heart rate data
+    let parsed = parse_with_budget(text.clone(), parse_options, app_state.config().parse_timeout).await;
+    let ParsedData { records, gap_count, ambiguous_count, nonexistent_count, device_id: body_device_id, .. } = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            error!("Failed to parse heart rate data: {:?}", e);
+            return Err(e.into_http_error().into());
+        }
+    };
+
+    let device_id = resolve_device_id(&app_state.config(), explicit_device_id, body_device_id)?;
+
+    // A dry run writes nothing, so it neither trips nor holds the debounce window
+    let debounce_slot = if params.dry_run {
+        None
+    } else {
+        Some(
+            app_state
+                .check_debounce(&device_id)
+                .map_err(|remaining| debounced_error(&device_id, remaining))?,
+        )
+    };
+
+    info!("Parsed {} heart rate records ({} gaps)", records.len(), gap_count);
+
+    let mut warnings = local_time_warnings(ambiguous_count, nonexistent_count);
+    let mut dropped_count = 0;
+
+    let (records, rejected) = reject_before_min_timestamp(records, app_state.config().min_timestamp, &mut warnings);
+    dropped_count += rejected;
+    let (records, expired_count) = drop_expired(records, app_state.config().max_age, &mut warnings);
+    dropped_count += expired_count;
+    let (records, jump_count) = drop_implausible_jumps(records, app_state.config().max_hr_jump_per_sec, &mut warnings);
+    dropped_count += jump_count;
+
+    // Remove or smooth single-sample sensor spikes
+    let mut filtered_count = 0;
+    let mut records = match outlier_filter {
+        Some(filter) => {
+            let (records, count) = filter.apply(records, app_state.config().outlier_max_deviation);
+            info!("Outlier filter {:?} affected {} records", filter, count);
+            filtered_count = count;
+            records
+        }
+        None => records,
+    };
+
+    if params.disambiguate_dupes {
+        let moved = disambiguate_duplicates(&mut records, precision.unit());
+        info!("Disambiguated {} duplicate timestamps", moved);
+    }
+
+    // Skip readings an overlapping earlier upload already wrote
+    let (mut records, duplicate_count) = app_state.drop_recently_written(&device_id, records);
+    if duplicate_count > 0 {
+        info!("Skipped {} records already written for {}", duplicate_count, device_id);
+    }
+
+    if params.with_delta {
+        compute_deltas(&mut records);
+    }
+
+    let truncated_count = count_truncated(&records, precision, &mut warnings);
+
+    if records.is_empty() {
+        // Nothing new is not an error when everything was already written
+        if duplicate_count > 0 {
+            return Ok(ResponseJson(ApiResponse {
+                success: true,
+                message: format!("All {} records were already written", duplicate_count),
+                dropped_count,
+                filtered_count,
+                gap_count,
+                truncated_count,
+                expired_count,
+                jump_count,
+                duplicate_count,
+                warnings,
+                ..Default::default()
+            }.cap_warnings(app_state.config().max_warnings)).into_response());
+        }
+        if app_state.config().empty_as_error {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "No valid heart rate records found".to_string(),
+            ).into());
+        }
+        return Ok(ResponseJson(ApiResponse {
+            success: false,
+            message: "No valid heart rate records found".to_string(),
+            dropped_count,
+            filtered_count,
+            gap_count,
+            truncated_count,
+            expired_count,
+            jump_count,
+            duplicate_count,
+            warnings,
+            ..Default::default()
+        }.cap_warnings(app_state.config().max_warnings)).into_response());
+    }
+
+    // Convert to InfluxDB Line Protocol
+    let line_options = LineOptions {
+        measurement: request_measurement(
+            &app_state.config(),
+            params.measurement.as_deref(),
+            params.measurement_per_device,
+            &device_id,
+        ),
+        store_offset: params.store_offset,
+        with_zone: params.with_zone,
+        precision,
+        ingested_at: params.with_ingest_time.then(Utc::now),
+    };
+
+    let tags = request_tags(&app_state, &device_id, user_id.as_deref(), &extra_tags);
+    let tags: Vec<(&str, &str)> = tags.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+
+    let lines: Vec<String> = records
+        .iter()
+        .map(|record| to_influxdb_line(record, &tags, &app_state.config(), &line_options))
+        .collect();
+
+    // Drop oversized lines so one corrupt record can't get the whole batch rejected; their
+    // records go too, so returned records and the histogram match what is written
+    let max_line_length = app_state.config().max_line_length;
+    let generated_count = lines.len();
+    let ((records, line_times), mut lines): ((Vec<_>, Vec<_>), Vec<_>) = lines
+        .into_iter()
+        .zip(records)
+        .enumerate()
+        .filter_map(|(i, (line, record))| {
+            if line.len() > max_line_length {
+                let warning = format!(
+                    "Dropped record {}: line length {} exceeds maximum {}",
+                    i + 1,
+                    line.len(),
+                    max_line_length
+                );
+                warn!("{}", warning);
+                warnings.push(warning);
+                None
+            } else {
+                let timestamp = record.timestamp;
+                Some(((record, timestamp), line))
+            }
+        })
+        .unzip();
+    dropped_count += generated_count - lines.len();
+
+    if lines.is_empty() {
+        let message = "All records were dropped as oversized lines".to_string();
+        if app_state.config().empty_as_error {
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, message).into());
+        }
+        return Ok(ResponseJson(ApiResponse {
+            success: false,
+            message,
+            dropped_count,
+            filtered_count,
+            gap_count,
+            truncated_count,
+            expired_count,
+            jump_count,
+            duplicate_count,
+            warnings,
+            ..Default::default()
+        }.cap_warnings(app_state.config().max_warnings)).into_response());
+    }
+
+    info!("Generated {} InfluxDB lines", lines.len());
+
+    // Only print first few lines for debugging
+    debug!("First few InfluxDB lines:");
+    for (i, line) in lines.iter().take(3).enumerate() {
+        debug!("  {}: {}", i + 1, Redact(line));
+    }
+    if lines.len() > 3 {
+        debug!("  ... and {} more lines", lines.len() - 3);
+    }
+
+    // Show what would be sent without sending it
+    if params.dry_run {
+        let preview = preview_write_request(&app_state.config(), &lines, precision);
+        info!("Dry run: {} {} ({} lines not sent)", preview.method, preview.url, preview.line_count);
+        return Ok(ResponseJson(ApiResponse {
+            success: true,
+            message: format!("Dry run: {} lines were not sent", lines.len()),
+            dropped_count,
+            filtered_count,
+            gap_count,
+            truncated_count,
+            expired_count,
+            jump_count,
+            duplicate_count,
+            warnings,
+            dry_run: Some(preview),
+            ..Default::default()
+        }.cap_warnings(app_state.config().max_warnings)).into_response());
+    }
+
+    let mut processed_count = lines.len();
+
+    // Audit the upload in the same batch as its records
+    let audit_line = if app_state.config().upload_audit {
+        let audit_line = upload_audit_line(&app_state.config(), &text, &tags, processed_count, precision)
+            .await
+            .map_err(|e| {
+                error!("Upload audit failed: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("Upload audit failed: {}", e))
+            })?;
+        Some(audit_line)
+    } else {
+        None
+    };
+
+    // Send to GreptimeDB through the flush queue
+    let mut days = Vec::new();
+    let write_time = if params.split_by_day {
+        let line_days = line_times.iter().map(DateTime::date_naive).collect();
+        let outcome = write_by_day(&app_state, line_days, lines, audit_line, precision).await;
+        if outcome.processed_count == 0 {
+            // Every day failed; report it like a single failed write
+            return Err(outcome.first_error.unwrap_or(EnqueueError::FlusherUnavailable).into_http_error());
+        }
+        for day in outcome.days.iter().filter(|day| !day.success) {
+            warnings.push(format!(
+                "Write for {} failed ({} records): {}",
+                day.date,
+                day.processed_count,
+                day.error.as_deref().unwrap_or("unknown error")
+            ));
+        }
+        let written_days: HashSet<&str> = outcome
+            .days
+            .iter()
+            .filter(|day| day.success)
+            .map(|day| day.date.as_str())
+            .collect();
+        app_state.remember_written(
+            &device_id,
+            line_times
+                .into_iter()
+                .filter(|time| written_days.contains(time.date_naive().to_string().as_str())),
+        );
+        processed_count = outcome.processed_count;
+        days = outcome.days;
+        outcome.write_time
+    } else {
+        lines.extend(audit_line);
+        let write_time = enqueue_write(&app_state, lines, precision)
+            .await
+            .map_err(EnqueueError::into_http_error)?;
+        app_state.remember_written(&device_id, line_times);
+        write_time
+    };
+    app_state.mark_seen(&device_id);
+    if let Some(slot) = debounce_slot {
+        slot.commit();
+    }
+
+    info!("=== Processing Complete ===");
+
+    let histogram = params
+        .histogram
+        .then(|| build_histogram(records.iter().map(|record| record.value), bucket_width));
+
+    let partial = dropped_count > 0 || !warnings.is_empty();
+    let status = if partial && params.multi_status.unwrap_or(app_state.config().multi_status_on_warnings) {
+        StatusCode::MULTI_STATUS
+    } else {
+        StatusCode::OK
+    };
+
+    if params.return_records && csv_output {
+        return Ok(with_greptime_ms((status, records_csv_response(&records)).into_response(), write_time));
+    }
+
+    let returned_records = params
+        .return_records
+        .then(|| records.iter().map(RecordOutput::from).collect());
+
+    let response = (status, ResponseJson(ApiResponse {
+        success: true,
+        message: success_message(&app_state.config(), processed_count),
+        processed_count,
+        dropped_count,
+        filtered_count,
+        gap_count,
+        truncated_count,
+        expired_count,
+        jump_count,
+        duplicate_count,
+        warnings,
+        omitted_warnings: 0,
+        histogram,
+        records: returned_records,
+        dry_run: None,
+        days,
+    }.cap_warnings(app_state.config().max_warnings))).into_response();
+
+    Ok(with_greptime_ms(response, write_time))
+}
+
+#[derive(Debug, Serialize)]
+struct DiagnoseResponse {
+    // Layout of values and timestamps: alternating, values_first, timestamps_first, mixed or empty
+    detected_layout: &'static str,
+    // Pairing strategy that was applied
+    strategy: &'static str,
+    // Timezone naive local timestamps were read in
+    timezone: String,
+    line_counts: LineStats,
+    record_count: usize,
+    gap_count: usize,
+    ambiguous_count: usize,
+    nonexistent_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    // The first few parsed records
+    sample_records: Vec<RecordOutput>,
+}
+
+const DIAGNOSE_SAMPLE_RECORDS: usize = 5;
+
+// Parse a sample export with the request's options and explain what the parser saw; nothing is written
+async fn diagnose(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    Query(params): Query<QueryParams>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<ResponseJson<DiagnoseResponse>, ApiError> {
+
+    reject_unknown_params(&app_state.config(), raw_query.as_deref(), QUERY_PARAM_KEYS)?;
+    authorize(&app_state.config(), &headers, params.token.as_deref())?;
+    let mut parse_options = request_parse_options(&app_state.config(), &params)?;
+    parse_options.json_input = is_json_content_type(headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()));
+
+    let text: Arc<str> = decode_body(&body)?.into();
+
+    let strategy = parse_options.strategy.as_str();
+    let timezone = match parse_options.local_tz {
+        Some(tz) => tz.to_string(),
+        None => "UTC+08:00".to_string(),
+    };
+
+    let parsed = parse_with_budget(text, parse_options, app_state.config().parse_timeout)
+        .await
+        .map_err(ParseFailure::into_http_error)?;
+
+    Ok(ResponseJson(DiagnoseResponse {
+        detected_layout: parsed.layout,
+        strategy,
+        timezone,
+        line_counts: parsed.line_stats,
+        record_count: parsed.records.len(),
+        gap_count: parsed.gap_count,
+        ambiguous_count: parsed.ambiguous_count,
+        nonexistent_count: parsed.nonexistent_count,
+        device_id: parsed.device_id,
+        sample_records: parsed
+            .records
+            .iter()
+            .take(DIAGNOSE_SAMPLE_RECORDS)
+            .map(RecordOutput::from)
+            .collect(),
+    }))
+}
+
+// /heart-rate for large uploads, reporting progress as Server-Sent Events: `parsed`, one `batch`
+// per write, then a final `summary` (or `error`). Options that shape a single response or write
+// (STREAM_UNSUPPORTED_PARAM_KEYS) are rejected rather than ignored.
+async fn process_heart_rate_stream(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    Query(params): Query<QueryParams>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Sse<impl futures_util::Stream<Item = Result<Event, Infallible>>>, ApiError> {
+
+    reject_unknown_params(&app_state.config(), raw_query.as_deref(), QUERY_PARAM_KEYS)?;
+    reject_stream_params(raw_query.as_deref())?;
+    authorize(&app_state.config(), &headers, params.token.as_deref())?;
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    check_content_type(&app_state.config(), content_type)?;
+
+    // Same body handling as /heart-rate: multipart file uploads and JSON arrays are accepted
+    let is_multipart = content_type.is_some_and(|value| value.starts_with("multipart/form-data"));
+    let (body, form_device_id) = if is_multipart {
+        let upload = extract_multipart_upload(&headers, body).await?;
+        (upload.file, upload.device_id)
+    } else {
+        (body, None)
+    };
+
+    let text = decode_body(&body)?;
+
+    let user_id = params.user_id.clone().or_else(|| {
+        headers
+            .get("x-user-id")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string())
+    });
+    if let Some(user_id) = &user_id {
+        validate_user_id(user_id)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid user_id: {}", e)))?;
+    }
+    if let Some(measurement) = &params.measurement {
+        check_measurement(&app_state.config(), measurement)?;
+    }
+    let extra_tags = parse_extra_tags(&app_state.config(), params.tags.as_deref())?;
+
+    let mut parse_options = request_parse_options(&app_state.config(), &params)?;
+    parse_options.json_input = is_json_content_type(content_type);
+    let precision = request_precision(&app_state.config(), params.precision.as_deref())?;
+
+    let (events, receiver) = mpsc::channel(16);
+    let request = StreamRequest { params, form_device_id, parse_options, precision, user_id, extra_tags };
+    tokio::spawn(stream_heart_rate(app_state, text, request, events));
+
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|event| (Ok(event), receiver))
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+// Request settings validated by process_heart_rate_stream before the task starts
+struct StreamRequest {
+    params: QueryParams,
+    // device_id field of a multipart upload
+    form_device_id: Option<String>,
+    parse_options: ParseOptions,
+    precision: Precision,
+    user_id: Option<String>,
+    extra_tags: Vec<(String, String)>,
+}
+
+// Processing task behind /heart-rate/stream; stops early if the client goes away.
+// The whole body is parsed and paired before it is cut into STREAM_BATCH_SIZE batches,
+// so a value and its timestamp always land in the same batch and no pair is split.
+async fn stream_heart_rate(
+    app_state: AppState,
+    text: String,
+    request: StreamRequest,
+    events: mpsc::Sender<Event>,
+) {
+    let StreamRequest { params, form_device_id, parse_options, precision, user_id, extra_tags } = request;
+    let emit = |name: &'static str, data: serde_json::Value| {
+        events.send(Event::default().event(name).data(data.to_string()))
+    };
+
+    let text: Arc<str> = text.into();
+    let parsed = parse_with_budget(text.clone(), parse_options, app_state.config().parse_timeout).await;
+    let ParsedData { records, gap_count, ambiguous_count, nonexistent_count, device_id: body_device_id, .. } = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let _ = emit("error", serde_json::json!({ "message": e.into_http_error().1 })).await;
+            return;
+        }
+    };
+
+    let device_id = match resolve_device_id(&app_state.config(), params.device_id.or(form_device_id), body_device_id) {
+        Ok(device_id) => device_id,
+        Err((_, message)) => {
+            let _ = emit("error", serde_json::json!({ "message": message })).await;
+            return;
+        }
+    };
+    let mut debounce_slot = match app_state.check_debounce(&device_id) {
+        Ok(slot) => Some(slot),
+        Err(remaining) => {
+            let _ = emit("error", serde_json::json!({ "message": debounced_error(&device_id, remaining).1 })).await;
+            return;
+        }
+    };
+    let line_options = LineOptions {
+        measurement: request_measurement(
+            &app_state.config(),
+            params.measurement.as_deref(),
+            params.measurement_per_device,
+            &device_id,
+        ),
+        store_offset: params.store_offset,
+        with_zone: params.with_zone,
+        precision,
+        ingested_at: params.with_ingest_time.then(Utc::now),
+    };
+
+    let mut warnings = local_time_warnings(ambiguous_count, nonexistent_count);
+    let parsed_count = records.len();
+    let (records, rejected) = reject_before_min_timestamp(records, app_state.config().min_timestamp, &mut warnings);
+    let (records, expired_count) = drop_expired(records, app_state.config().max_age, &mut warnings);
+    let (records, jump_count) = drop_implausible_jumps(records, app_state.config().max_hr_jump_per_sec, &mut warnings);
+    let rejected = rejected + expired_count + jump_count;
+    let (records, duplicate_count) = app_state.drop_recently_written(&device_id, records);
+    let truncated_count = count_truncated(&records, precision, &mut warnings);
+
+    if emit("parsed", serde_json::json!({
+        "parsed": parsed_count,
+        "rejected": rejected,
+        "gap_count": gap_count,
+        "duplicate_count": duplicate_count,
+    })).await.is_err() {
+        return;
+    }
+
+    let tags = request_tags(&app_state, &device_id, user_id.as_deref(), &extra_tags);
+    let tags: Vec<(&str, &str)> = tags.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+    let lines: Vec<String> = records
+        .iter()
+        .map(|record| to_influxdb_line(record, &tags, &app_state.config(), &line_options))
+        .collect();
+
+    // Audit the upload in the same write as its last batch
+    let mut audit_line = None;
+    if app_state.config().upload_audit && !lines.is_empty() {
+        match upload_audit_line(&app_state.config(), &text, &tags, lines.len(), precision).await {
+            Ok(line) => audit_line = Some(line),
+            Err(e) => {
+                error!("Upload audit failed: {}", e);
+                let _ = emit("error", serde_json::json!({ "message": format!("Upload audit failed: {}", e) })).await;
+                return;
+            }
+        }
+    }
+
+    // Batches are cut from finished records, one line per pair
+    let mut sent = 0;
+    let batches = lines.chunks(app_state.config().stream_batch_size);
+    let batch_count = batches.len();
+
+    for (i, (batch, batch_records)) in batches.zip(records.chunks(app_state.config().stream_batch_size)).enumerate() {
+        let mut write = batch.to_vec();
+        if i + 1 == batch_count {
+            write.extend(audit_line.take());
+        }
+        if let Err(e) = enqueue_write(&app_state, write, precision).await {
+            error!("Streaming write failed on batch {}: {}", i + 1, e.for_log());
+            let _ = emit("error", serde_json::json!({ "message": e.to_string(), "batch": i + 1, "sent": sent })).await;
+            return;
+        }
+        sent += batch.len();
+        app_state.mark_seen(&device_id);
+        if let Some(slot) = debounce_slot.take() {
+            slot.commit();
+        }
+        app_state.remember_written(&device_id, batch_records.iter().map(|record| record.timestamp));
+
+        let progress = serde_json::json!({ "batch": i + 1, "batches": batch_count, "lines": batch.len(), "sent": sent });
+        if emit("batch", progress).await.is_err() {
+            return;
+        }
+    }
+
+    let summary = serde_json::json!({
+        // Everything having been written before still counts as success
+        "success": sent > 0 || duplicate_count > 0,
+        "processed_count": sent,
+        "dropped_count": rejected,
+        "gap_count": gap_count,
+        "truncated_count": truncated_count,
+        "expired_count": expired_count,
+        "jump_count": jump_count,
+        "duplicate_count": duplicate_count,
+        "batches": batch_count,
+        "warnings": warnings.iter().take(app_state.config().max_warnings).collect::<Vec<_>>(),
+        "omitted_warnings": warnings.len().saturating_sub(app_state.config().max_warnings),
+    });
+    let _ = emit("summary", summary).await;
+}
+
+// Shed requests beyond MAX_INFLIGHT_REQUESTS with 503 instead of queueing them
+async fn limit_inflight(
+    axum::extract::State(inflight): axum::extract::State<Arc<tokio::sync::Semaphore>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Ok(_permit) = inflight.try_acquire_owned() else {
+        warn!("Too many requests in flight, rejecting {} {}", request.method(), request.uri().path());
+        return (StatusCode::SERVICE_UNAVAILABLE, "Too many requests in flight, try again later").into_response();
+    };
+    next.run(request).await
+}
+
+// Measure total handler time and report it in the X-Processing-Ms response header
+async fn processing_time(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let mut response = next.run(request).await;
+    let elapsed_ms = started.elapsed().as_millis();
+
+    if let Ok(value) = HeaderValue::from_str(&elapsed_ms.to_string()) {
+        response.headers_mut().insert("x-processing-ms", value);
+    }
+
+    response
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckAuthParams {
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckAuthResponse {
+    // Whether GreptimeDB accepted the configured credentials
+    authenticated: bool,
+    greptime_status: u16,
+    message: String,
+}
+
+// Send a trivial authenticated query to GreptimeDB to confirm the configured credentials work.
+// 200 either way once GreptimeDB answered; 502 when it couldn't be reached.
+async fn check_auth(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    Query(params): Query<CheckAuthParams>,
+    headers: HeaderMap,
+) -> Result<ResponseJson<CheckAuthResponse>, (StatusCode, String)> {
+
+    authorize(&app_state.config(), &headers, params.token.as_deref())?;
+
+    let url = format!("{}/v1/sql", app_state.config().greptime_url);
+    let response = app_state
+        .greptime_request(reqwest::Method::POST, &url)
+        .form(&[("sql", "SELECT 1")])
+        .send()
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("Failed to reach GreptimeDB: {}", e)))?;
+
+    let status = response.status();
+    let (authenticated, message) = if status.is_success() {
+        (true, "GreptimeDB accepted the credentials".to_string())
+    } else if matches!(status.as_u16(), 401 | 403) {
+        (false, "GreptimeDB rejected the credentials".to_string())
+    } else {
+        let error_text = read_error_body(response, app_state.config().error_body_limit).await;
+        (false, format!("GreptimeDB answered {}: {}", status.as_u16(), error_text))
+    };
+    info!("Credential check: {}", message);
+
+    Ok(ResponseJson(CheckAuthResponse {
+        authenticated,
+        greptime_status: status.as_u16(),
+        message,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ReloadParams {
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReloadResponse {
+    success: bool,
+    message: String,
+}
+
+// Re-read CONFIG_FILE and the environment and swap in the new configuration. Parsing and
+// validation settings apply to the next request; connection, writer and route settings
+// are fixed at startup and still need a restart.
+async fn reload_config(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    Query(params): Query<ReloadParams>,
+    headers: HeaderMap,
+) -> Result<ResponseJson<ReloadResponse>, (StatusCode, String)> {
+
+    authorize_destructive(&app_state.config(), &headers, params.token.as_deref())?;
+
+    let config_file = app_state.config_file.clone();
+    let source = match &config_file {
+        Some(path) => {
+            let source = ConfigSource::from_file(path).map_err(|e| {
+                error!("Failed to reload config file {}: {}", path, e);
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read config file {}: {}", path, e))
+            })?;
+            info!("Read {} settings from {}", source.file.len(), path);
+            source
+        }
+        None => ConfigSource::default(),
+    };
+
+    let config = Config::from_source(&source);
+    REDACT_VALUES.store(config.redact_values, Ordering::Relaxed);
+    *app_state.config.write().unwrap() = Arc::new(config);
+    app_state.reload_device_names();
+
+    info!("Configuration reloaded");
+
+    Ok(ResponseJson(ReloadResponse {
+        success: true,
+        message: match config_file {
+            Some(path) => format!("Configuration reloaded from the environment and {}", path),
+            None => "Configuration reloaded from the environment".to_string(),
+        },
+    }))
+}
+
+// A failed write to GreptimeDB, kept for /health/ready
+#[derive(Debug, Clone, Serialize)]
+struct WriteFailure {
+    message: String,
+    at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReadyResponse {
+    ready: bool,
+    // Set while the latest write (or the startup probe) failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<WriteFailure>,
+}
+
+// Readiness: 503 until the first successful write or startup probe
+async fn readiness_check(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+) -> (StatusCode, ResponseJson<ReadyResponse>) {
+    let ready = app_state.ready.load(Ordering::Relaxed);
+    let last_error = app_state.last_write_error.read().unwrap().clone();
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, ResponseJson(ReadyResponse { ready, last_error }))
+}
+
+// Mark the service ready as soon as GreptimeDB answers a trivial query
+async fn run_startup_probe(app_state: AppState) {
+    match run_sql(&app_state, "SELECT 1").await {
+        Ok(_) => {
+            info!("Startup probe succeeded, reporting ready");
+            app_state.ready.store(true, Ordering::Relaxed);
+        }
+        Err(e) => {
+            warn!("Startup probe failed, waiting for the first write: {}", e);
+            app_state.record_write_result(Some(format!("Startup probe failed: {}", e)));
+        }
+    }
+}
+
+// Report the write time of the batch a request's lines went out in
+fn with_greptime_ms(mut response: Response, write_time: Duration) -> Response {
+    if let Ok(value) = HeaderValue::from_str(&write_time.as_millis().to_string()) {
+        response.headers_mut().insert("x-greptime-ms", value);
+    }
+    response
+}
+
+async fn metrics(axum::extract::State(app_state): axum::extract::State<AppState>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        app_state.metrics.render(),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    uptime_secs: u64,
+    // Devices the watchdog currently considers silent
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    silent_devices: Vec<String>,
+}
+
+async fn health_check(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+) -> ResponseJson<HealthResponse> {
+    ResponseJson(HealthResponse {
+        status: "ok",
+        uptime_secs: app_state.started_at.elapsed().as_secs(),
+        silent_devices: app_state
+            .silent_devices(Instant::now())
+            .into_iter()
+            .map(|(device_id, _)| device_id)
+            .collect(),
+    })
+}
+
+fn build_router(app_state: AppState) -> Router {
+    let routes = Router::new()
+        .route(
+            "/heart-rate",
+            post(process_heart_rate_text).layer(middleware::from_fn(processing_time)),
+        )
+        .route("/heart-rate/stream", post(process_heart_rate_stream))
+        .route("/diagnose", post(diagnose))
+        .route("/tz-test", axum::routing::get(tz_test))
+        .route("/write-raw", post(write_raw_line_protocol))
+        .route("/devices", axum::routing::get(list_devices))
+        .route("/device/:device_id", axum::routing::delete(delete_device_data))
+        .route("/metrics", axum::routing::get(metrics))
+        .route("/reload", post(reload_config))
+        .route("/check-auth", axum::routing::get(check_auth));
+
+    // MAX_INFLIGHT_REQUESTS covers everything but the probes, so a busy instance isn't
+    // taken for a dead one
+    let routes = match app_state.config().max_inflight_requests {
+        Some(limit) => routes.layer(middleware::from_fn_with_state(
+            Arc::new(tokio::sync::Semaphore::new(limit)),
+            limit_inflight,
+        )),
+        None => routes,
+    };
+    let routes = routes
+        .route("/health", axum::routing::get(health_check))
+        .route("/health/ready", axum::routing::get(readiness_check));
+
+    // Mount everything under ROUTE_PREFIX (e.g. /api/heartbeat) when configured
+    let prefix = app_state.config().route_prefix.clone();
+
+    let routes = if prefix.is_empty() {
+        routes
+    } else {
+        Router::new().nest(&prefix, routes)
+    };
+
+    // gzip per Accept-Encoding; the default predicate leaves SSE streams and tiny bodies alone
+    let routes = if app_state.config().compress_responses {
+        routes.layer(CompressionLayer::new())
+    } else {
+        routes
+    };
+
+    routes
+        .layer(
+            ServiceBuilder::new()
+                .layer(CatchPanicLayer::custom(handle_panic))
+                .layer(CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any))
+        )
+        .with_state(app_state)
+}
+
+// Longest prefix of `text` of at most `max_bytes` bytes that doesn't split a character
+fn truncate_at_char_boundary(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+// Turn a handler panic into a 500 JSON response instead of a dropped connection
+fn handle_panic(panic: Box<dyn std::any::Any + Send + 'static>) -> Response {
+    let detail = panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+    error!("Handler panicked: {}", detail);
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ResponseJson(ApiResponse {
+            success: false,
+            message: "Internal server error".to_string(),
+            ..Default::default()
+        }),
+    )
+        .into_response()
+}
+
+// Normalize a route prefix to "/segment/..." without a trailing slash; "" or "/" means none
+fn normalize_route_prefix(prefix: &str) -> String {
+    let trimmed = prefix.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+// Set from REDACT_VALUES at startup; read wherever heart-rate data is logged
+static REDACT_VALUES: AtomicBool = AtomicBool::new(false);
+
+// Heart-rate data in log output: shown as-is, or as <redacted> when REDACT_VALUES is set
+// so verbose logs keep their counts and structure without the PII
+struct Redact<T>(T);
+
+impl<T: std::fmt::Display> std::fmt::Display for Redact<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if REDACT_VALUES.load(Ordering::Relaxed) {
+            f.write_str("<redacted>")
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+// Tracing subscriber writing pretty text, or one JSON object per line when `json` is set;
+// RUST_LOG filters levels and defaults to info
+fn log_subscriber<W>(json: bool, writer: W) -> Box<dyn tracing::Subscriber + Send + Sync>
+where
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer);
+
+    if json {
+        Box::new(builder.json().finish())
+    } else {
+        Box::new(builder.finish())
+    }
+}
+
+// Install the global tracing subscriber. LOG_FORMAT selects "pretty" (default) or "json" output.
+fn init_logging() {
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let json = std::env::var("LOG_FORMAT").unwrap_or_default().trim().eq_ignore_ascii_case("json");
+    log_subscriber(json, std::io::stdout).init();
+}
+
+#[tokio::main]
+async fn main() {
+    init_logging();
+
+    let source = match config_file_path() {
+        Some(path) => ConfigSource::from_file(&path).unwrap_or_else(|e| {
+            error!("Failed to read config file {}: {}", path, e);
+            ConfigSource::default()
+        }),
+        None => ConfigSource::default(),
+    };
+
+    let config = Config::from_source(&source);
+    let port = config.port;
+    REDACT_VALUES.store(config.redact_values, Ordering::Relaxed);
+
+    info!("Starting heart rate proxy server...");
+    info!("GreptimeDB URL: {}", config.greptime_url);
+    if let Some(mirror_url) = &config.greptime_mirror_url {
+        info!("GreptimeDB mirror URL: {}", mirror_url);
+    }
+    info!("Database: {}", config.greptime_db);
+    info!("Write precision: {}", config.precision.as_str());
+    if config.value_transform != ValueTransform::default() {
+        info!("Value transform: x * {} + {}", config.value_transform.scale, config.value_transform.offset);
+    }
+    if let Some(tz) = config.local_tz {
+        info!("Local timezone: {}", tz);
+    }
+    info!(
+        "Timestamp formats: {}",
+        config.timestamp_formats.iter().map(TimestampFormat::as_str).collect::<Vec<_>>().join(", ")
+    );
+    if config.write_sink == "file" {
+        info!("Write sink: file ({})", config.sink_path);
+    } else if config.write_backend != "influxdb" {
+        info!("Write backend: {}", config.write_backend);
+    }
+    if config.write_sink != "file" && config.fallback_to_file {
+        info!("Failed writes fall back to file: {}", config.sink_path);
+    }
+    if config.write_method != reqwest::Method::POST {
+        info!("Write method: {}", config.write_method);
+    }
+    info!("GreptimeDB HTTP/2: {}", if config.greptime_http2 { "enabled" } else { "disabled" });
+    info!("Server port: {}", port);
+    info!("Write queue capacity: {}", config.write_queue_capacity);
+    info!("API token auth: {}", if config.api_token.is_some() { "enabled" } else { "disabled" });
+    if config.redact_values {
+        info!("Heart-rate values are redacted in logs");
+    }
+
+    let (write_tx, write_rx) = mpsc::channel(config.write_queue_capacity);
+    let app_state = AppState::new(config, write_tx);
+
+    let (flusher_shutdown, flusher_shutdown_rx) = oneshot::channel();
+    let flusher = tokio::spawn(run_flusher(app_state.clone(), write_rx, flusher_shutdown_rx));
+    let shutdown_flush_timeout = app_state.config().shutdown_flush_timeout;
+
+    if app_state.config().startup_probe {
+        tokio::spawn(run_startup_probe(app_state.clone()));
+    }
+
+    if let Some(threshold) = app_state.config().silent_device_after {
+        info!("Silent device watchdog: {}s", threshold.as_secs());
+        tokio::spawn(run_watchdog(app_state.clone()));
+    }
+
+    if let Some(at) = app_state.config().rollup_at {
+        info!("Daily rollup at {} UTC into {}", at.format("%H:%M"), DAILY_MEASUREMENT);
+        tokio::spawn(run_daily_rollup(app_state.clone(), at));
+    }
+
+    if app_state.config().device_map_path.is_some() {
+        app_state.reload_device_names();
+        #[cfg(unix)]
+        tokio::spawn(reload_device_names_on_sighup(app_state.clone()));
+    }
+
+    let route_prefix = app_state.config().route_prefix.clone();
+    let app = build_router(app_state.clone());
+
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
+        .await
+        .unwrap();
+
+    info!("Server running on http://0.0.0.0:{}{}", port, route_prefix);
+
+    let (signalled_tx, signalled_rx) = oneshot::channel();
+    let server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal(signalled_tx))
+            .await
+    });
+
+    // Errors if the server stopped on its own, which is handled the same way
+    let _ = signalled_rx.await;
+
+    // Let in-flight requests finish, then flush whatever they left in the queue
+    let drained = tokio::time::timeout(shutdown_flush_timeout, async {
+        match server.await {
+            Ok(Err(e)) => error!("Server error: {}", e),
+            Err(e) => error!("Server task failed: {}", e),
+            Ok(Ok(())) => {}
+        }
+        let _ = flusher_shutdown.send(());
+        let _ = flusher.await;
+        app_state.drain_mirror_writes().await;
+    })
+    .await;
+
+    match drained {
+        Ok(()) => info!("Write queue drained, exiting"),
+        Err(_) => warn!(
+            "Shutdown did not finish within {}ms, pending writes are lost",
+            shutdown_flush_timeout.as_millis()
+        ),
+    }
+}
+
+// Resolve on Ctrl+C or, on unix, SIGTERM, and report it through `signalled`
+async fn shutdown_signal(signalled: oneshot::Sender<()>) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to install Ctrl+C handler: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+
+    info!("Shutdown signal received");
+    let _ = signalled.send(());
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Config {
+        Config::from_source(&ConfigSource::default())
+    }
+
+    // App state with nothing draining its queue; the receiver stands in for the flusher
+    fn test_state(config: Config) -> (AppState, mpsc::Receiver<WriteJob>) {
+        let (write_tx, write_rx) = mpsc::channel(config.write_queue_capacity);
+        (AppState::new(config, write_tx), write_rx)
+    }
+
+    // Serve a router on an ephemeral local port and return its base URL
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        url
+    }
+
+    // The proxy itself, serving `app_state`
+    async fn serve_app(app_state: AppState) -> String {
+        serve(build_router(app_state)).await
+    }
+
+    // A request received by a mock GreptimeDB
+    #[derive(Debug, Clone)]
+    struct MockRequest {
+        method: String,
+        uri: String,
+        authorization: Option<String>,
+        body: String,
+    }
+
+    type MockRequests = Arc<std::sync::Mutex<Vec<MockRequest>>>;
+
+    // GreptimeDB stand-in recording every request and answering it with `respond`
+    async fn spawn_mock(
+        respond: impl Fn(&MockRequest) -> (StatusCode, String) + Clone + Send + Sync + 'static,
+    ) -> (String, MockRequests) {
+        let requests = MockRequests::default();
+        let recorded = requests.clone();
+        let router = Router::new().fallback(move |request: Request| {
+            let respond = respond.clone();
+            let recorded = recorded.clone();
+            async move {
+                let mock_request = MockRequest {
+                    method: request.method().to_string(),
+                    uri: request.uri().to_string(),
+                    authorization: request
+                        .headers()
+                        .get(header::AUTHORIZATION)
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string),
+                    body: String::new(),
+                };
+                let body = axum::body::to_bytes(request.into_body(), usize::MAX).await.unwrap();
+                let mock_request = MockRequest { body: String::from_utf8_lossy(&body).into_owned(), ..mock_request };
+                let response = respond(&mock_request);
+                recorded.lock().unwrap().push(mock_request);
+                response
+            }
+        });
+        (serve(router).await, requests)
+    }
+
+    // Mock answering every request with 204, like a successful GreptimeDB write
+    async fn spawn_ok_mock() -> (String, MockRequests) {
+        spawn_mock(|_| (StatusCode::NO_CONTENT, String::new())).await
+    }
+
+    // Server that accepts requests and never answers them
+    async fn serve_hanging() -> String {
+        serve(Router::new().fallback(std::future::pending::<StatusCode>)).await
+    }
+
+    // Poll until the mock has received `count` requests, or give up after a second
+    async fn wait_for_requests(requests: &MockRequests, count: usize) -> Vec<MockRequest> {
+        for _ in 0..100 {
+            if requests.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        requests.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn enqueue_waits_for_capacity_then_gives_up_with_503() {
+        let mut config = test_config();
+        config.write_queue_capacity = 1;
+        config.enqueue_timeout = Duration::from_millis(100);
+        let (app_state, mut write_rx) = test_state(config);
+
+        // Fills the only slot; its handler then waits for a write result that never comes
+        let first = tokio::spawn({
+            let app_state = app_state.clone();
+            async move { enqueue_write(&app_state, vec!["a".to_string()], Precision::Millis).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // Capacity freed while waiting lets the next job in
+        let second = tokio::spawn({
+            let app_state = app_state.clone();
+            async move { enqueue_write(&app_state, vec!["b".to_string()], Precision::Millis).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(write_rx.recv().await.unwrap().lines, ["a"]);
+        assert_eq!(write_rx.recv().await.unwrap().lines, ["b"]);
+
+        // With the queue full again, the handler waits out the timeout and gets a 503
+        let third = tokio::spawn({
+            let app_state = app_state.clone();
+            async move { enqueue_write(&app_state, vec!["c".to_string()], Precision::Millis).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let started = Instant::now();
+        let result = enqueue_write(&app_state, vec!["d".to_string()], Precision::Millis).await;
+        assert!(started.elapsed() >= Duration::from_millis(80));
+        let error = result.unwrap_err();
+        assert!(matches!(error, EnqueueError::QueueFull));
+        assert!(matches!(error.into_http_error(), ApiError::Text(StatusCode::SERVICE_UNAVAILABLE, _)));
+
+        for handle in [first, second, third] {
+            handle.abort();
+        }
+    }
+
+    #[test]
+    fn integer_values_are_rounded_half_away_from_zero() {
+        let mut config = test_config();
+        config.hr_as_integer = true;
+        assert_eq!(format_value(72.0, &config), "72i");
+        assert_eq!(format_value(72.5, &config), "73i");
+        assert_eq!(format_value(72.4, &config), "72i");
+    }
+
+    #[test]
+    fn float_values_use_hr_decimals() {
+        let mut config = test_config();
+        config.hr_as_integer = false;
+        config.hr_decimals = 1;
+        assert_eq!(format_value(72.5, &config), "72.5");
+        config.hr_decimals = 3;
+        assert_eq!(format_value(72.5, &config), "72.500");
+    }
+
+    #[test]
+    fn query_token_only_counts_when_allowed() {
+        let mut config = test_config();
+        config.api_token = Some("secret".to_string());
+        let no_headers = HeaderMap::new();
+
+        config.allow_query_token = false;
+        assert_eq!(authorize(&config, &no_headers, Some("secret")).unwrap_err().0, StatusCode::UNAUTHORIZED);
+
+        config.allow_query_token = true;
+        assert!(authorize(&config, &no_headers, Some("secret")).is_ok());
+        assert!(authorize(&config, &no_headers, Some("wrong")).is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        assert!(authorize(&config, &headers, None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn heart_rate_responses_report_processing_time() {
+        let (app_state, _write_rx) = test_state(test_config());
+        let url = serve_app(app_state).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/heart-rate?dry_run=true", url))
+            .body("72\n2025年6月2日 21:28\n")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let elapsed = response.headers()["x-processing-ms"].to_str().unwrap();
+        assert!(elapsed.parse::<u64>().is_ok(), "not numeric: {}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn mirror_receives_a_copy_without_delaying_the_primary() {
+        let (primary_url, primary_requests) = spawn_ok_mock().await;
+        let (mirror_url, mirror_requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.greptime_url = primary_url;
+        config.greptime_mirror_url = Some(mirror_url);
+        let (app_state, _write_rx) = test_state(config);
+
+        let lines = vec!["heart_rate,device_id=w value=72 1748870880000".to_string()];
+        send_to_greptime(&app_state, lines, Precision::Millis).await.unwrap();
+
+        for requests in [primary_requests, mirror_requests] {
+            let received = wait_for_requests(&requests, 1).await;
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0].method, "POST");
+            assert!(received[0].uri.starts_with("/v1/influxdb/api/v2/write?"));
+            assert_eq!(received[0].body, "heart_rate,device_id=w value=72 1748870880000");
+        }
+
+        // A mirror that never answers doesn't hold up the write
+        let mut config = (*app_state.config()).clone();
+        config.greptime_mirror_url = Some(serve_hanging().await);
+        let (app_state, _write_rx) = test_state(config);
+        let started = Instant::now();
+        send_to_greptime(&app_state, vec!["heart_rate value=72 1".to_string()], Precision::Millis).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn mirror_writes_are_bounded_and_drained() {
+        let (primary_url, _primary_requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.greptime_url = primary_url;
+        config.greptime_mirror_url = Some(serve_hanging().await);
+        config.max_mirror_writes = 1;
+        let (app_state, _write_rx) = test_state(config);
+
+        // The first write holds the only permit, so the second skips the mirror
+        for _ in 0..2 {
+            send_to_greptime(&app_state, vec!["heart_rate value=72 1".to_string()], Precision::Millis).await.unwrap();
+        }
+        assert_eq!(app_state.metrics.mirror_writes_dropped_total.get(), 1);
+        assert_eq!(app_state.mirror_writes.lock().unwrap().len(), 1);
+
+        // A mirror that answers is fully written once drained
+        let (mirror_url, mirror_requests) = spawn_ok_mock().await;
+        let mut config = (*app_state.config()).clone();
+        config.greptime_mirror_url = Some(mirror_url);
+        let (app_state, _write_rx) = test_state(config);
+        send_to_greptime(&app_state, vec!["heart_rate value=72 1".to_string()], Precision::Millis).await.unwrap();
+        app_state.drain_mirror_writes().await;
+        assert_eq!(mirror_requests.lock().unwrap().len(), 1);
+        assert!(app_state.mirror_writes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn greptime_requests_time_out() {
+        let mut config = test_config();
+        config.greptime_timeout = Some(Duration::from_millis(200));
+        let client = build_http_client(&config);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let result = runtime.block_on(async {
+            client.get(serve_hanging().await).send().await
+        });
+        assert!(result.unwrap_err().is_timeout());
+    }
+
+    #[tokio::test]
+    async fn empty_uploads_answer_422_only_with_empty_as_error() {
+        let client = reqwest::Client::new();
+        for (empty_as_error, expected) in [(true, 422), (false, 200)] {
+            let mut config = test_config();
+            config.empty_as_error = empty_as_error;
+            let (app_state, _write_rx) = test_state(config);
+            let url = serve_app(app_state).await;
+
+            let response = client.post(format!("{}/heart-rate", url)).body("no readings here\n").send().await.unwrap();
+            assert_eq!(response.status(), expected);
+            if !empty_as_error {
+                let body: serde_json::Value = response.json().await.unwrap();
+                assert_eq!(body["success"], false);
+            }
+        }
+    }
+
+    #[test]
+    fn delete_sql_quotes_the_device_and_bounds_the_range() {
+        let start = Utc.with_ymd_and_hms(2025, 6, 1, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2025, 6, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            build_delete_sql("heart_rate", "o'brien", start, end),
+            "DELETE FROM `heart_rate` WHERE device_id = 'o''brien' \
+             AND greptime_timestamp >= '2025-06-01T00:00:00.000Z' AND greptime_timestamp < '2025-06-02T00:00:00.000Z'"
+        );
+    }
+
+    #[test]
+    fn backoff_is_capped_and_scaled_by_jitter() {
+        let base = Duration::from_millis(100);
+        let max = Duration::from_millis(1000);
+        assert_eq!(backoff_delay(0, base, max, || 1.0), Duration::from_millis(100));
+        assert_eq!(backoff_delay(2, base, max, || 1.0), Duration::from_millis(400));
+        assert_eq!(backoff_delay(2, base, max, || 0.5), Duration::from_millis(200));
+        assert_eq!(backoff_delay(10, base, max, || 1.0), max);
+        assert_eq!(backoff_delay(3, base, max, || 0.0), Duration::ZERO);
+        // Out-of-range jitter is clamped
+        assert_eq!(backoff_delay(1, base, max, || 7.0), Duration::from_millis(200));
+    }
+
+    // POST a body to the proxy and return the status and JSON response
+    async fn post_json(url: &str, body: &str) -> (u16, serde_json::Value) {
+        let response = reqwest::Client::new().post(url).body(body.to_string()).send().await.unwrap();
+        let status = response.status().as_u16();
+        (status, response.json().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn device_and_user_ids_are_written_as_tags() {
+        let (app_state, _write_rx) = test_state(test_config());
+        let url = serve_app(app_state).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/heart-rate?dry_run=true&device_id=watch1", url))
+            .header("X-User-Id", "alice")
+            .body("72\n2025年6月2日 21:28\n")
+            .send()
+            .await
+            .unwrap();
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["dry_run"]["body_preview"],
+            "heart_rate,device_id=watch1,user_id=alice value=72.00 1748870880000"
+        );
+
+        let (_, body) = post_json(&format!("{}/heart-rate?dry_run=true&user_id=bob", url), "72\n2025年6月2日 21:28\n").await;
+        assert_eq!(
+            body["dry_run"]["body_preview"],
+            "heart_rate,device_id=apple-watch,user_id=bob value=72.00 1748870880000"
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_lines_are_dropped_and_counted() {
+        let mut config = test_config();
+        config.max_line_length = "heart_rate,device_id=apple-watch value=72.00 1748870880000".len();
+        let (app_state, _write_rx) = test_state(config);
+        let url = serve_app(app_state).await;
+
+        let (status, body) = post_json(
+            &format!("{}/heart-rate?dry_run=true", url),
+            "72\n2025年6月2日 21:28\n100\n2025年6月2日 21:29\n",
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["dropped_count"], 1);
+        assert_eq!(body["dry_run"]["line_count"], 1);
+        assert!(body["warnings"][0].as_str().unwrap().starts_with("Dropped record 2: line length"));
+    }
+
+    // Fresh path in the temp directory, unique to this test run
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("heart-rate-proxy-{}-{}-{}", std::process::id(), n, name))
+    }
+
+    #[tokio::test]
+    async fn mapped_devices_get_a_device_name_tag() {
+        let path = temp_path("devices.json");
+        std::fs::write(&path, r#"{"watch1": "Alice Watch"}"#).unwrap();
+        let mut config = test_config();
+        config.device_map_path = Some(path.to_string_lossy().into_owned());
+        let (app_state, _write_rx) = test_state(config);
+        app_state.reload_device_names();
+        let url = serve_app(app_state).await;
+
+        let (_, body) = post_json(&format!("{}/heart-rate?dry_run=true&device_id=watch1", url), "72\n2025年6月2日 21:28\n").await;
+        assert_eq!(
+            body["dry_run"]["body_preview"],
+            "heart_rate,device_id=watch1,device_name=Alice\\ Watch value=72.00 1748870880000"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn histogram_groups_values_into_buckets() {
+        assert_eq!(
+            build_histogram([61.0, 69.9, 70.0, 85.0, 88.0, 89.0], 10.0),
+            vec![
+                HistogramBucket { start: 60.0, end: 70.0, count: 2 },
+                HistogramBucket { start: 70.0, end: 80.0, count: 1 },
+                HistogramBucket { start: 80.0, end: 90.0, count: 3 },
+            ]
+        );
+        assert!(build_histogram([], 10.0).is_empty());
+    }
+
+    #[test]
+    fn nanosecond_timestamps_round_trip() {
+        let time = Utc.with_ymd_and_hms(2025, 6, 2, 13, 28, 0).unwrap() + chrono::Duration::nanoseconds(123_456_789);
+        let nanos = Precision::Nanos.timestamp(&time);
+        assert_eq!(nanos, 1_748_870_880_123_456_789);
+        assert_eq!(Utc.timestamp_nanos(nanos), time);
+        assert!(!Precision::Nanos.truncates(&time));
+        assert!(Precision::Micros.truncates(&time));
+    }
+
+    fn record(value: f64, timestamp: DateTime<Utc>) -> HeartRateRecord {
+        HeartRateRecord {
+            value,
+            timestamp,
+            offset_minutes: 0,
+            delta: None,
+            range: None,
+            confidence: None,
+            source_line: None,
+        }
+    }
+
+    #[test]
+    fn records_before_min_ts_are_rejected() {
+        let min_timestamp = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
+        let records = vec![
+            record(72.0, Utc.with_ymd_and_hms(1969, 12, 31, 23, 59, 0).unwrap()),
+            record(75.0, Utc.with_ymd_and_hms(2025, 6, 2, 13, 28, 0).unwrap()),
+        ];
+        let mut warnings = Vec::new();
+        let (records, rejected) = reject_before_min_timestamp(records, min_timestamp, &mut warnings);
+        assert_eq!(rejected, 1);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].value, 75.0);
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn multipart_uploads_take_the_file_and_device_fields() {
+        let (app_state, _write_rx) = test_state(test_config());
+        let url = serve_app(app_state).await;
+
+        let body = "--XYZ\r\n\
+            Content-Disposition: form-data; name=\"device_id\"\r\n\r\n\
+            watch2\r\n\
+            --XYZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"export.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            72\n2025年6月2日 21:28\n\r\n\
+            --XYZ--\r\n";
+        let response = reqwest::Client::new()
+            .post(format!("{}/heart-rate?dry_run=true", url))
+            .header("Content-Type", "multipart/form-data; boundary=XYZ")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["dry_run"]["body_preview"], "heart_rate,device_id=watch2 value=72.00 1748870880000");
+    }
+
+    #[tokio::test]
+    async fn health_reports_status_and_uptime() {
+        let (app_state, _write_rx) = test_state(test_config());
+        let url = serve_app(app_state).await;
+
+        let response = reqwest::get(format!("{}/health", url)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["status"], "ok");
+        assert!(body["uptime_secs"].is_u64());
+        // Only present while devices are silent
+        assert!(body.get("silent_devices").is_none());
+    }
+
+    #[test]
+    fn chinese_am_pm_markers_use_the_12_hour_clock() {
+        let at = |hour, minute| chrono::NaiveDate::from_ymd_opt(2025, 6, 2).unwrap().and_hms_opt(hour, minute, 0).unwrap();
+        assert_eq!(parse_chinese_datetime("2025年6月2日 上午9:05"), Some(at(9, 5)));
+        assert_eq!(parse_chinese_datetime("2025年6月2日 下午9:05"), Some(at(21, 5)));
+        assert_eq!(parse_chinese_datetime("2025年6月2日上午12:30"), Some(at(0, 30)));
+        assert_eq!(parse_chinese_datetime("2025年6月2日 下午12:30"), Some(at(12, 30)));
+        assert_eq!(parse_chinese_datetime("2025年6月2日 21:28"), Some(at(21, 28)));
+        assert_eq!(parse_chinese_datetime("2025年6月2日 下午13:00"), None);
+    }
+
+    #[tokio::test]
+    async fn greptime_http2_uses_prior_knowledge() {
+        // The mock only speaks HTTP/1.1
+        let (url, _requests) = spawn_ok_mock().await;
+
+        let client = build_http_client(&test_config());
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_11);
+
+        let mut config = test_config();
+        config.greptime_http2 = true;
+        config.greptime_keepalive = Some(Duration::from_secs(5));
+        let client = build_http_client(&config);
+        // Prior knowledge means no fallback to HTTP/1.1
+        assert!(client.get(&url).send().await.is_err());
+    }
+
+    // Run the flusher for `app_state`; sending on the returned channel shuts it down
+    fn spawn_flusher(app_state: &AppState, write_rx: mpsc::Receiver<WriteJob>) -> (oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        (shutdown, tokio::spawn(run_flusher(app_state.clone(), write_rx, shutdown_rx)))
+    }
+
+    // Proxy writing to `greptime_url` through a running flusher
+    async fn serve_writing_app(mut config: Config, greptime_url: &str) -> (String, AppState, oneshot::Sender<()>) {
+        config.greptime_url = greptime_url.to_string();
+        let (app_state, write_rx) = test_state(config);
+        let (shutdown, _flusher) = spawn_flusher(&app_state, write_rx);
+        (serve_app(app_state.clone()).await, app_state, shutdown)
+    }
+
+    #[test]
+    fn inject_tag_goes_after_the_escaped_series_key() {
+        assert_eq!(inject_tag("cpu value=1 10", "device_id", "w 1"), "cpu,device_id=w\\ 1 value=1 10");
+        assert_eq!(inject_tag("my\\ cpu,host=a value=1", "device_id", "w"), "my\\ cpu,host=a,device_id=w value=1");
+        assert_eq!(inject_tag("no_fields", "device_id", "w"), "no_fields");
+    }
+
+    #[tokio::test]
+    async fn raw_line_protocol_is_forwarded_verbatim() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+
+        let body = "# comment\nheart_rate,device_id=x value=72i 1748870880000\r\n\nspo2 value=98 1748870880000\n";
+        let (status, response) = post_json(&format!("{}/write-raw", url), body).await;
+        assert_eq!(status, 200);
+        assert_eq!(response["processed_count"], 2);
+        assert_eq!(
+            requests.lock().unwrap()[0].body,
+            "heart_rate,device_id=x value=72i 1748870880000\nspo2 value=98 1748870880000"
+        );
+
+        let (status, _) = post_json(&format!("{}/write-raw?device_id=w", url), "spo2 value=98 1\n").await;
+        assert_eq!(status, 200);
+        assert_eq!(requests.lock().unwrap()[1].body, "spo2,device_id=w value=98 1");
+    }
+
+    #[test]
+    fn missing_database_errors_are_recognized() {
+        assert!(is_database_not_found(r#"{"error":"Database not found: heartbeat_test"}"#));
+        assert!(is_database_not_found("Unknown database 'x'"));
+        assert!(!is_database_not_found("table not found"));
+        assert_eq!(build_create_database_sql("heartbeat_test"), "CREATE DATABASE IF NOT EXISTS `heartbeat_test`");
+        assert_eq!(build_create_database_sql("a`b"), "CREATE DATABASE IF NOT EXISTS `a``b`");
+    }
+
+    fn default_parse_options() -> ParseOptions {
+        ParseOptions::from_config(&test_config())
+    }
+
+    #[test]
+    fn source_offset_is_kept_and_written_on_request() {
+        let parsed = parse_heart_rate_data("72\n2025-06-02T21:28:00+05:30\n75\n2025年6月2日 21:29\n", &default_parse_options()).unwrap();
+        let offsets: Vec<i32> = parsed.records.iter().map(|record| record.offset_minutes).collect();
+        // Sorted by time: 21:29 UTC+8 comes before 21:28 UTC+5:30
+        assert_eq!(offsets, [480, 330]);
+
+        let options = LineOptions {
+            measurement: DEFAULT_MEASUREMENT.to_string(),
+            store_offset: true,
+            precision: Precision::Millis,
+            ..Default::default()
+        };
+        let line = to_influxdb_line(&parsed.records[1], &[], &test_config(), &options);
+        assert_eq!(line, "heart_rate value=72.00,offset_minutes=330i 1748879880000");
+    }
+
+    #[test]
+    fn noise_prefixes_are_stripped() {
+        let prefixes = vec!["HR:".to_string(), "TS:".to_string()];
+        assert_eq!(strip_line_prefix("HR: 72", &prefixes), "72");
+        assert_eq!(strip_line_prefix("TS:2025年6月2日 21:28", &prefixes), "2025年6月2日 21:28");
+        assert_eq!(strip_line_prefix("72", &prefixes), "72");
+
+        let mut options = default_parse_options();
+        options.strip_prefixes = prefixes;
+        let parsed = parse_heart_rate_data("HR: 72\nTS: 2025年6月2日 21:28\n", &options).unwrap();
+        assert_eq!(parsed.records.len(), 1);
+        assert_eq!(parsed.records[0].value, 72.0);
+    }
+
+    #[test]
+    fn clock_offset_shifts_timestamps_within_bounds() {
+        let mut options = default_parse_options();
+        options.clock_offset = chrono::Duration::seconds(120);
+        let parsed = parse_heart_rate_data("72\n2025年6月2日 21:28\n", &options).unwrap();
+        assert_eq!(parsed.records[0].timestamp, Utc.with_ymd_and_hms(2025, 6, 2, 13, 30, 0).unwrap());
+
+        assert_eq!(clamp_clock_offset(120), 120);
+        assert_eq!(clamp_clock_offset(-10_000_000), -MAX_CLOCK_OFFSET_SECS);
+        assert_eq!(clamp_clock_offset(i64::MAX), MAX_CLOCK_OFFSET_SECS);
+    }
+
+    #[test]
+    fn greptime_client_errors_pass_their_status_through() {
+        for status in [400, 401] {
+            let error = EnqueueError::Write(WriteError::Status { status, body: "nope".to_string() });
+            match error.into_http_error() {
+                ApiError::Greptime(code, body) => {
+                    assert_eq!(code.as_u16(), status);
+                    assert_eq!(body.greptime_status, Some(status));
+                    assert_eq!(body.greptime_error, "nope");
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        match EnqueueError::Write(WriteError::Request("refused".to_string())).into_http_error() {
+            ApiError::Greptime(code, body) => {
+                assert_eq!(code, StatusCode::BAD_GATEWAY);
+                assert_eq!(body.greptime_status, None);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn file_writer_appends_newline_terminated_batches() {
+        let path = temp_path("sink.lp");
+        let writer = FileWriter::new(path.to_string_lossy().into_owned());
+        writer.append(vec!["a value=1 1".to_string(), "a value=2 2".to_string()]).await.unwrap();
+        writer.append(vec!["a value=3 3".to_string()]).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a value=1 1\na value=2 2\na value=3 3\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Records one minute apart with the given values
+    fn records_per_minute(values: &[f64]) -> Vec<HeartRateRecord> {
+        let start = Utc.with_ymd_and_hms(2025, 6, 2, 13, 0, 0).unwrap();
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| record(*value, start + chrono::Duration::minutes(i as i64)))
+            .collect()
+    }
+
+    #[test]
+    fn median_filter_removes_a_single_spike() {
+        let filter = OutlierFilter::parse("median:5").unwrap();
+        let (records, dropped) = filter.apply(records_per_minute(&[72.0, 73.0, 180.0, 74.0, 72.0]), 30.0);
+        assert_eq!(dropped, 1);
+        let values: Vec<f64> = records.iter().map(|record| record.value).collect();
+        assert_eq!(values, [72.0, 73.0, 74.0, 72.0]);
+
+        assert!(OutlierFilter::parse("median:2").is_err());
+        assert!(OutlierFilter::parse("mode:5").is_err());
+    }
+
+    #[test]
+    fn deltas_are_relative_to_the_previous_record() {
+        let mut records = records_per_minute(&[72.0, 75.0, 70.5]);
+        compute_deltas(&mut records);
+        let deltas: Vec<Option<f64>> = records.iter().map(|record| record.delta).collect();
+        assert_eq!(deltas, [Some(0.0), Some(3.0), Some(-4.5)]);
+    }
+
+    // GreptimeDB SQL API result holding `rows`
+    fn sql_result(rows: serde_json::Value) -> String {
+        serde_json::json!({
+            "output": [{"records": {"schema": {"column_schemas": []}, "rows": rows}}],
+            "execution_time_ms": 1,
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn devices_are_listed_from_the_sql_api() {
+        let (greptime_url, requests) = spawn_mock(|request| {
+            let rows = if request.body.starts_with("sql=SHOW+TABLES") {
+                serde_json::json!([["heart_rate"]])
+            } else {
+                serde_json::json!([["w1"], ["w2"]])
+            };
+            (StatusCode::OK, sql_result(rows))
+        })
+        .await;
+        let mut config = test_config();
+        config.greptime_url = greptime_url;
+        let (app_state, _write_rx) = test_state(config);
+        let url = serve_app(app_state).await;
+
+        let body: serde_json::Value = reqwest::get(format!("{}/devices", url)).await.unwrap().json().await.unwrap();
+        assert_eq!(body["devices"], serde_json::json!(["w1", "w2"]));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[1].uri, "/v1/sql?db=heartbeat_test");
+        assert_eq!(requests[1].body, "sql=SELECT+DISTINCT+device_id+FROM+%60heart_rate%60");
+
+        assert!(sql_rows(&serde_json::json!({"output": []})).is_empty());
+    }
+
+    #[test]
+    fn duplicate_timestamps_are_nudged_apart() {
+        let at = Utc.with_ymd_and_hms(2025, 6, 2, 13, 0, 0).unwrap();
+        let later = at + chrono::Duration::minutes(1);
+        let mut records = vec![record(70.0, at), record(71.0, at), record(72.0, at), record(73.0, later)];
+        let moved = disambiguate_duplicates(&mut records, Precision::Millis.unit());
+        assert_eq!(moved, 2);
+        let times: Vec<DateTime<Utc>> = records.iter().map(|record| record.timestamp).collect();
+        assert_eq!(
+            times,
+            [at, at + chrono::Duration::milliseconds(1), at + chrono::Duration::milliseconds(2), later]
+        );
+    }
+
+    // Log output captured in memory
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn greptime_error_bodies_are_redacted_in_logs() {
+        let _guard = REDACT_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let rejected = || EnqueueError::Write(WriteError::Status {
+            status: 400,
+            body: "invalid line: heart_rate,device_id=w1 value=72".to_string(),
+        });
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        REDACT_VALUES.store(true, Ordering::Relaxed);
+        tracing::subscriber::with_default(log_subscriber(false, move || writer.clone()), || {
+            let _ = rejected().into_http_error();
+            error!("Write failed: {}", rejected().for_log());
+        });
+        REDACT_VALUES.store(false, Ordering::Relaxed);
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("GreptimeDB rejected the write with 400: <redacted>"), "{}", output);
+        assert!(output.contains("GreptimeDB error: <redacted>"), "{}", output);
+        assert!(!output.contains("value=72"), "{}", output);
+        assert_eq!(rejected().for_log(), "GreptimeDB error: invalid line: heart_rate,device_id=w1 value=72");
+    }
+
+    #[test]
+    fn json_logs_are_one_object_per_line() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        tracing::subscriber::with_default(log_subscriber(true, move || writer.clone()), || {
+            info!("Parsed {} heart rate records", 2);
+            warn!(device_id = "w1", "Device \"quoted\" went silent");
+        });
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let entries: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["level"], "INFO");
+        assert_eq!(entries[0]["fields"]["message"], "Parsed 2 heart rate records");
+        assert_eq!(entries[1]["fields"]["device_id"], "w1");
+        assert_eq!(entries[1]["fields"]["message"], "Device \"quoted\" went silent");
+    }
+
+    #[tokio::test]
+    async fn routes_are_mounted_under_route_prefix() {
+        let mut config = test_config();
+        config.route_prefix = normalize_route_prefix("/api/heartbeat/");
+        let (app_state, _write_rx) = test_state(config);
+        let url = serve_app(app_state).await;
+
+        assert_eq!(reqwest::get(format!("{}/api/heartbeat/health", url)).await.unwrap().status(), 200);
+        assert_eq!(reqwest::get(format!("{}/health", url)).await.unwrap().status(), 404);
+        assert_eq!(normalize_route_prefix("/"), "");
+    }
+
+    #[test]
+    fn ranges_use_their_midpoint() {
+        assert_eq!(parse_value_range("68-74", false), Some((68.0, 74.0)));
+
+        let mut options = default_parse_options();
+        options.range_mode = RangeMode::Midpoint;
+        let parsed = parse_heart_rate_data("68-74\n2025年6月2日 21:28\n", &options).unwrap();
+        assert_eq!(parsed.records[0].value, 71.0);
+        assert_eq!(parsed.records[0].range, None);
+
+        options.range_mode = RangeMode::MinMax;
+        let parsed = parse_heart_rate_data("68-74\n2025年6月2日 21:28\n", &options).unwrap();
+        assert_eq!(parsed.records[0].value, 71.0);
+        assert_eq!(parsed.records[0].range, Some((68.0, 74.0)));
+
+        options.range_mode = RangeMode::Off;
+        let parsed = parse_heart_rate_data("68-74\n2025年6月2日 21:28\n", &options).unwrap();
+        assert!(parsed.records.is_empty());
+    }
+
+    #[tokio::test]
+    async fn return_records_echoes_the_written_records() {
+        let (greptime_url, _requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+
+        let (status, body) = post_json(
+            &format!("{}/heart-rate?return_records=true", url),
+            "72\n2025年6月2日 21:28\n75.333\n2025年6月2日 21:29\n",
+        )
+        .await;
+        assert_eq!(status, 200);
+        let records = body["records"].as_array().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["value"], 72.0);
+        assert_eq!(records[0]["timestamp"], "2025-06-02T13:28:00Z");
+        assert_eq!(records[1]["value"], 75.333);
+
+        let (_, body) = post_json(&format!("{}/heart-rate", url), "72\n2025年6月2日 21:30\n").await;
+        assert!(body.get("records").is_none());
+    }
+
+    #[tokio::test]
+    async fn returned_records_leave_out_oversized_lines() {
+        let (greptime_url, _requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.max_line_length = "heart_rate,device_id=apple-watch value=72.00 1748870880000".len();
+        let (url, _app_state, _shutdown) = serve_writing_app(config, &greptime_url).await;
+
+        let (status, body) = post_json(
+            &format!("{}/heart-rate?return_records=true&histogram=true", url),
+            "72\n2025年6月2日 21:28\n100\n2025年6月2日 21:29\n",
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["dropped_count"], 1);
+        let records = body["records"].as_array().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["value"], 72.0);
+        let histogram_total: u64 = body["histogram"].as_array().unwrap().iter().map(|bucket| bucket["count"].as_u64().unwrap()).sum();
+        assert_eq!(histogram_total, 1);
+    }
+
+    #[tokio::test]
+    async fn device_delete_and_listing_cover_per_device_tables() {
+        // heart_rate_w1 exists, the shared heart_rate table doesn't
+        let (greptime_url, requests) = spawn_mock(|request| {
+            let body = request.body.as_str();
+            if body.starts_with("sql=SHOW+TABLES") {
+                (StatusCode::OK, sql_result(serde_json::json!([["heart_rate_daily"], ["heart_rate_w1"], ["heart_rate_w2"]])))
+            } else if body.contains("%60heart_rate%60") {
+                (StatusCode::BAD_REQUEST, r#"{"error":"Table not found: greptime.public.heart_rate"}"#.to_string())
+            } else if body.starts_with("sql=DELETE") {
+                (StatusCode::OK, r#"{"output":[{"affectedrows":3}]}"#.to_string())
+            } else {
+                let device = if body.contains("w1") { "w1" } else { "w2" };
+                (StatusCode::OK, sql_result(serde_json::json!([[device]])))
+            }
+        })
+        .await;
+        let mut config = test_config();
+        config.greptime_url = greptime_url;
+        config.api_token = Some("secret".to_string());
+        let (app_state, _write_rx) = test_state(config);
+        let url = serve_app(app_state).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .delete(format!("{}/device/w1?start=2025-06-01T00:00:00Z&end=2025-06-03T00:00:00Z", url))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["deleted_count"], 3);
+        assert!(requests.lock().unwrap()[1].body.starts_with("sql=DELETE+FROM+%60heart_rate_w1%60"));
+
+        let body: serde_json::Value =
+            client.get(format!("{}/devices", url)).bearer_auth("secret").send().await.unwrap().json().await.unwrap();
+        assert_eq!(body["devices"], serde_json::json!(["w1", "w2"]));
+
+        let response =
+            client.get(format!("{}/devices?measurement=bad-name", url)).bearer_auth("secret").send().await.unwrap();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[test]
+    fn device_tables_exclude_the_rollup_and_other_measurements() {
+        assert!(is_device_table("heart_rate", "heart_rate"));
+        assert!(is_device_table("heart_rate_w1", "heart_rate"));
+        assert!(!is_device_table("heart_rate_daily", "heart_rate"));
+        assert!(!is_device_table("heart_rates", "heart_rate"));
+    }
+
+    #[tokio::test]
+    async fn error_bodies_are_truncated_at_the_limit() {
+        let (url, _requests) = spawn_mock(|_| (StatusCode::INTERNAL_SERVER_ERROR, "x".repeat(10_000))).await;
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(read_error_body(response, 100).await, format!("{}... (truncated)", "x".repeat(100)));
+
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(read_error_body(response, 20_000).await.len(), 10_000);
+    }
+
+    #[tokio::test]
+    async fn dry_run_sends_nothing_and_returns_the_request() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+
+        let (status, body) = post_json(&format!("{}/heart-rate?dry_run=true", url), "72\n2025年6月2日 21:28\n").await;
+        assert_eq!(status, 200);
+        let preview = &body["dry_run"];
+        assert_eq!(preview["method"], "POST");
+        assert_eq!(
+            preview["url"],
+            format!("{}/v1/influxdb/api/v2/write?db=heartbeat_test&precision=ms", greptime_url)
+        );
+        assert_eq!(preview["line_count"], 1);
+        assert_eq!(preview["headers"]["Content-Type"], WRITE_CONTENT_TYPE);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    // Server-Sent Events in a response body as (event, data) pairs
+    fn sse_events(body: &str) -> Vec<(String, serde_json::Value)> {
+        body.split("\n\n")
+            .filter(|event| !event.trim().is_empty())
+            .map(|event| {
+                let field = |name: &str| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string()
+                };
+                (field("event:"), serde_json::from_str(&field("data:")).unwrap())
+            })
+            .collect()
+    }
+
+    // POST to /heart-rate/stream and collect the events once the stream ends
+    async fn post_stream(url: &str, body: &str) -> Vec<(String, serde_json::Value)> {
+        let response = reqwest::Client::new().post(url).body(body.to_string()).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        sse_events(&response.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn stream_reports_parsed_batch_and_summary_events() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+
+        let events = post_stream(
+            &format!("{}/heart-rate/stream?device_id=w1", url),
+            "72\n2025年6月2日 21:28\n75\n2025年6月2日 21:29\n",
+        )
+        .await;
+        let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["parsed", "batch", "summary"]);
+        assert_eq!(events[0].1["parsed"], 2);
+        assert_eq!(events[1].1["sent"], 2);
+        assert_eq!(events[2].1["success"], true);
+        assert_eq!(events[2].1["processed_count"], 2);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stream_rejects_params_it_cannot_honor() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/heart-rate/stream?dry_run=true&filter=median:5&device_id=w1", url))
+            .body("72\n2025年6月2日 21:28\n")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+        assert_eq!(
+            response.text().await.unwrap(),
+            "Query parameter(s) not supported on /heart-rate/stream: dry_run, filter; use /heart-rate instead"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stream_accepts_json_bodies_and_audits_uploads() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.upload_audit = true;
+        let (url, _app_state, _shutdown) = serve_writing_app(config, &greptime_url).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/heart-rate/stream?device_id=w1", url))
+            .header("Content-Type", "application/json")
+            .body(r#"[{"bpm": 72, "time": "2025-06-02T13:28:00Z"}]"#)
+            .send()
+            .await
+            .unwrap();
+        let events = sse_events(&response.text().await.unwrap());
+        assert_eq!(events.last().unwrap().1["processed_count"], 1);
+
+        let body = requests.lock().unwrap()[0].body.clone();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "heart_rate,device_id=w1 value=72.00 1748870880000");
+        assert!(lines[1].starts_with("upload_audit,device_id=w1 sha256=\""));
+    }
+
+    #[tokio::test]
+    async fn strict_query_params_reject_unknown_keys() {
+        let mut config = test_config();
+        config.strict_query_params = true;
+        let (app_state, _write_rx) = test_state(config);
+        let url = serve_app(app_state).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post(format!("{}/heart-rate?dry_run=true&devce_id=w1&histgram=true", url))
+            .body("72\n2025年6月2日 21:28\n")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+        assert_eq!(response.text().await.unwrap(), "Unknown query parameter(s): devce_id, histgram");
+
+        let response = client
+            .post(format!("{}/heart-rate?dry_run=true&device_id=w1", url))
+            .body("72\n2025年6月2日 21:28\n")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn devices_past_the_threshold_are_silent() {
+        let now = Instant::now();
+        let last_seen: HashMap<String, Instant> = [
+            ("recent".to_string(), now - Duration::from_secs(10)),
+            ("quiet".to_string(), now - Duration::from_secs(120)),
+            ("gone".to_string(), now - Duration::from_secs(600)),
+        ]
+        .into_iter()
+        .collect();
+
+        let silent = silent_devices(&last_seen, now, Duration::from_secs(60));
+        assert_eq!(
+            silent,
+            [("gone".to_string(), Duration::from_secs(600)), ("quiet".to_string(), Duration::from_secs(120))]
+        );
+    }
+
+    #[test]
+    fn line_protocol_body_skips_blank_lines() {
+        let lines = vec!["a value=1 1\r\n".to_string(), "  ".to_string(), "a value=2 2".to_string()];
+        assert_eq!(line_protocol_body(&lines, false), "a value=1 1\na value=2 2");
+        assert_eq!(line_protocol_body(&lines, true), "a value=1 1\na value=2 2\n");
+        assert_eq!(line_protocol_body(&[], true), "");
+    }
+
+    #[test]
+    fn gap_sentinels_are_counted_not_recorded() {
+        let mut options = default_parse_options();
+        options.gap_sentinels = vec![0.0, -1.0];
+        let parsed = parse_heart_rate_data(
+            "72\n2025年6月2日 21:28\n0\n2025年6月2日 21:29\n-1\n2025年6月2日 21:30\n75\n2025年6月2日 21:31\n",
+            &options,
+        )
+        .unwrap();
+        assert_eq!(parsed.gap_count, 2);
+        let values: Vec<f64> = parsed.records.iter().map(|record| record.value).collect();
+        assert_eq!(values, [72.0, 75.0]);
+        // The gaps kept their timestamps, so 75 still pairs with 21:31
+        assert_eq!(parsed.records[1].timestamp, Utc.with_ymd_and_hms(2025, 6, 2, 13, 31, 0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn upload_audit_line_hashes_and_keeps_the_body() {
+        let dir = temp_path("audit");
+        let mut config = test_config();
+        config.upload_audit_blob_dir = Some(dir.to_string_lossy().into_owned());
+        let text = "72\n2025年6月2日 21:28\n";
+
+        let line = upload_audit_line(&config, text, &[("device_id", "w 1")], 1, Precision::Millis).await.unwrap();
+        let hash = sha256_hex(text.as_bytes());
+        let blob = dir.join(format!("{}.txt", hash));
+        assert!(line.starts_with(&format!(
+            "upload_audit,device_id=w\\ 1 sha256=\"{}\",bytes={}i,record_count=1i,blob_path=\"{}\" ",
+            hash,
+            text.len(),
+            blob.display()
+        )));
+        assert_eq!(std::fs::read_to_string(&blob).unwrap(), text);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dst_transitions_resolve_predictably() {
+        let berlin: chrono_tz::Tz = "Europe/Berlin".parse().unwrap();
+        let naive = |text| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap();
+
+        // 02:30 doesn't exist on spring-forward day; read with the winter offset
+        let (time, resolution) = resolve_local_time(naive("2025-03-30 02:30"), Some(berlin)).unwrap();
+        assert_eq!(resolution, LocalResolution::Nonexistent);
+        assert_eq!(time.with_timezone(&Utc), Utc.with_ymd_and_hms(2025, 3, 30, 1, 30, 0).unwrap());
+
+        // 02:30 happens twice on fall-back day; the earlier (summer time) instant wins
+        let (time, resolution) = resolve_local_time(naive("2025-10-26 02:30"), Some(berlin)).unwrap();
+        assert_eq!(resolution, LocalResolution::Ambiguous);
+        assert_eq!(time.with_timezone(&Utc), Utc.with_ymd_and_hms(2025, 10, 26, 0, 30, 0).unwrap());
+
+        let (time, resolution) = resolve_local_time(naive("2025-06-02 21:28"), None).unwrap();
+        assert_eq!(resolution, LocalResolution::Exact);
+        assert_eq!(time.offset().local_minus_utc(), 8 * 3600);
+    }
+
+    #[tokio::test]
+    async fn parsing_past_the_budget_times_out() {
+        // The parser stalls until released, so it can't beat the budget
+        let (release, stalled) = std::sync::mpsc::channel::<()>();
+        let result = run_with_budget(Some(Duration::from_millis(1)), move || {
+            let _ = stalled.recv();
+            Ok(ParsedData::default())
+        })
+        .await;
+        drop(release);
+        let (status, message) = result.unwrap_err().into_http_error();
+        assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(message, "Parsing exceeded the 1 ms budget");
+
+        let parsed = parse_with_budget("72\n2025年6月2日 21:28\n".into(), default_parse_options(), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(parsed.records.len(), 1);
+    }
+
+    #[test]
+    fn device_line_in_the_body_names_the_device() {
+        let mut options = default_parse_options();
+        options.device_line_pattern = Some(regex::Regex::new(r"^Device:\s*(\S+)$").unwrap());
+        let parsed = parse_heart_rate_data("Device: watch7\n72\n2025年6月2日 21:28\n", &options).unwrap();
+        assert_eq!(parsed.device_id.as_deref(), Some("watch7"));
+        assert_eq!(parsed.records.len(), 1);
+
+        let config = test_config();
+        assert_eq!(resolve_device_id(&config, None, parsed.device_id.clone()).unwrap(), "watch7");
+        assert_eq!(resolve_device_id(&config, Some("query".to_string()), parsed.device_id).unwrap(), "query");
+        assert_eq!(resolve_device_id(&config, None, None).unwrap(), DEFAULT_DEVICE_ID);
+    }
+
+    #[tokio::test]
+    async fn partial_success_answers_207_when_asked() {
+        let (greptime_url, _requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+        let body = "72\n1999-01-01T00:00:00Z\n75\n2025年6月2日 21:28\n";
+
+        let (status, response) = post_json(&format!("{}/heart-rate?multi_status=true", url), body).await;
+        assert_eq!(status, 207);
+        assert_eq!(response["processed_count"], 1);
+        assert_eq!(response["dropped_count"], 1);
+
+        let (status, _) = post_json(&format!("{}/heart-rate?device_id=other", url), body).await;
+        assert_eq!(status, 200);
+    }
+
+    #[tokio::test]
+    async fn successful_writes_report_greptime_time() {
+        let (greptime_url, _requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/heart-rate", url))
+            .body("72\n2025年6月2日 21:28\n")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let write_ms = response.headers()["x-greptime-ms"].to_str().unwrap();
+        assert!(write_ms.parse::<u64>().is_ok(), "not numeric: {}", write_ms);
+    }
+
+    #[tokio::test]
+    async fn split_by_day_writes_each_day_separately() {
+        // Rejects the batch for June 2 (UTC)
+        let (greptime_url, requests) = spawn_mock(|request| {
+            if request.body.contains(" 17488") {
+                (StatusCode::BAD_REQUEST, "bad day".to_string())
+            } else {
+                (StatusCode::NO_CONTENT, String::new())
+            }
+        })
+        .await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+
+        let (status, body) = post_json(
+            &format!("{}/heart-rate?split_by_day=true", url),
+            "70\n2025-06-01T10:00:00Z\n71\n2025-06-01T11:00:00Z\n72\n2025-06-02T13:28:00Z\n73\n2025-06-03T09:00:00Z\n",
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        assert_eq!(body["processed_count"], 3);
+        let days: Vec<(&str, bool, u64)> = body["days"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|day| (day["date"].as_str().unwrap(), day["success"].as_bool().unwrap(), day["processed_count"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(days, [("2025-06-01", true, 2), ("2025-06-02", false, 1), ("2025-06-03", true, 1)]);
+    }
+
+    #[tokio::test]
+    async fn format_describes_returned_records_not_the_body() {
+        let (app_state, _write_rx) = test_state(test_config());
+        let url = serve_app(app_state).await;
+        let client = reqwest::Client::new();
+        let send = |query: &'static str| {
+            client
+                .post(format!("{}/heart-rate?dry_run=true&{}", url, query))
+                .header("Content-Type", "text/plain")
+                .body("72\n2025年6月2日 21:28\n")
+                .send()
+        };
+
+        let response = send("format=json").await.unwrap();
+        assert_eq!(response.status(), 400);
+        assert!(response.text().await.unwrap().contains("requires return_records=true"));
+
+        let response = send("format=json&return_records=true").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::CONTENT_TYPE.as_str()], "application/json");
+    }
+
+    #[test]
+    fn pairing_strategies_match_values_to_timestamps() {
+        use EntryKind::{Timestamp as T, Value as V};
+        let order = [V, T, V, V, T, T];
+        assert_eq!(pair_entries(&order, PairingStrategy::Sequential), [(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(pair_entries(&order, PairingStrategy::Alternating), [(0, 0), (2, 1)]);
+        assert_eq!(pair_entries(&order, PairingStrategy::ValuesFirst), [(0, 0)]);
+        assert_eq!(pair_entries(&order, PairingStrategy::TimestampsFirst), []);
+        assert_eq!(pair_entries(&order, PairingStrategy::Proportional), [(0, 0), (1, 1), (2, 2)]);
+
+        assert_eq!(pair_entries(&[V, V, T, T], PairingStrategy::ValuesFirst), [(0, 0), (1, 1)]);
+        assert_eq!(pair_entries(&[T, T, V, V], PairingStrategy::TimestampsFirst), [(0, 0), (1, 1)]);
+        assert_eq!(pair_entries(&[T, V, T, V], PairingStrategy::Alternating), [(0, 0), (1, 1)]);
+        assert_eq!(pair_entries(&[], PairingStrategy::Alternating), []);
+    }
+
+    #[test]
+    fn confidence_is_split_off_the_value() {
+        assert_eq!(split_confidence("72 confidence=0.9"), ("72", Some(0.9)));
+        assert_eq!(split_confidence("72 (conf: .75)"), ("72", Some(0.75)));
+        assert_eq!(split_confidence("72 [CONFIDENCE=1]"), ("72", Some(1.0)));
+        assert_eq!(split_confidence("72"), ("72", None));
+
+        let mut options = default_parse_options();
+        options.parse_confidence = true;
+        let parsed = parse_heart_rate_data("72 confidence=0.9\n2025年6月2日 21:28\n", &options).unwrap();
+        assert_eq!(parsed.records[0].value, 72.0);
+        assert_eq!(parsed.records[0].confidence, Some(0.9));
+    }
+
+    #[test]
+    fn truncation_never_splits_a_character() {
+        // "年" is three bytes, straddling byte 500
+        let text = format!("{}年{}", "a".repeat(499), "b".repeat(10));
+        assert_eq!(truncate_at_char_boundary(&text, 500), "a".repeat(499));
+        assert_eq!(truncate_at_char_boundary(&text, 502), format!("{}年", "a".repeat(499)));
+        assert_eq!(truncate_at_char_boundary("short", 500), "short");
+    }
+
+    #[test]
+    fn sort_false_keeps_source_order() {
+        let body = "75\n2025年6月2日 21:29\n72\n2025年6月2日 21:28\n";
+        let values = |options: &ParseOptions| -> Vec<f64> {
+            parse_heart_rate_data(body, options).unwrap().records.iter().map(|record| record.value).collect()
+        };
+
+        let mut options = default_parse_options();
+        assert_eq!(values(&options), [72.0, 75.0]);
+        options.preserve_order = true;
+        assert_eq!(values(&options), [75.0, 72.0]);
+    }
+
+    #[test]
+    fn proportional_pairing_drops_the_middle_value() {
+        assert_eq!(proportional_positions(9, 10), [0, 1, 2, 3, 5, 6, 7, 8, 9]);
+        assert_eq!(proportional_positions(1, 10), [0]);
+        assert!(proportional_positions(0, 10).is_empty());
+
+        let mut order = vec![EntryKind::Value; 10];
+        order.extend([EntryKind::Timestamp; 9]);
+        let pairs = pair_entries(&order, PairingStrategy::Proportional);
+        assert_eq!(pairs, [(0, 0), (1, 1), (2, 2), (3, 3), (5, 4), (6, 5), (7, 6), (8, 7), (9, 8)]);
+    }
+
+    #[test]
+    fn value_transform_applies_before_range_checks() {
+        let transform = ValueTransform { scale: 2.0, offset: 0.0 };
+        assert_eq!(transform.apply(36.0), 72.0);
+        assert_eq!(ValueTransform { scale: 2.0, offset: -1.0 }.apply(36.0), 71.0);
+
+        // 20 is below MIN_HEART_RATE, but it becomes 40 after scaling
+        let mut options = default_parse_options();
+        options.value_transform = transform;
+        let parsed = parse_heart_rate_data("20\n2025年6月2日 21:28\n", &options).unwrap();
+        assert_eq!(parsed.records[0].value, 40.0);
+    }
+
+    #[tokio::test]
+    async fn readiness_passes_after_the_first_write() {
+        let (greptime_url, _requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+
+        let response = reqwest::get(format!("{}/health/ready", url)).await.unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.json::<serde_json::Value>().await.unwrap()["ready"], false);
+
+        let (status, _) = post_json(&format!("{}/heart-rate", url), "72\n2025年6月2日 21:28\n").await;
+        assert_eq!(status, 200);
+
+        let response = reqwest::get(format!("{}/health/ready", url)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.json::<serde_json::Value>().await.unwrap()["ready"], true);
+    }
+
+    #[test]
+    fn content_types_are_matched_against_the_allowlist() {
+        let mut config = test_config();
+        assert!(check_content_type(&config, Some("image/png")).is_ok());
+
+        config.allowed_content_types = vec!["text/*".to_string(), "multipart/form-data".to_string()];
+        assert!(check_content_type(&config, Some("text/plain; charset=utf-8")).is_ok());
+        assert!(check_content_type(&config, Some("Text/CSV")).is_ok());
+        assert!(check_content_type(&config, Some("multipart/form-data; boundary=x")).is_ok());
+
+        let (status, message) = check_content_type(&config, Some("application/json")).unwrap_err();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(message, "Content-Type application/json is not accepted; allowed: text/*, multipart/form-data");
+        assert!(check_content_type(&config, None).unwrap_err().1.starts_with("Content-Type none"));
+    }
+
+    #[tokio::test]
+    async fn diagnose_explains_the_parse() {
+        let (app_state, _write_rx) = test_state(test_config());
+        let url = serve_app(app_state).await;
+
+        let (status, body) = post_json(
+            &format!("{}/diagnose?strategy=alternating", url),
+            "72\n2025年6月2日 21:28\nnoise\n75\n2025年6月2日 21:29\n",
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["detected_layout"], "alternating");
+        assert_eq!(body["strategy"], "alternating");
+        assert_eq!(body["timezone"], "UTC+08:00");
+        assert_eq!(body["record_count"], 2);
+        assert_eq!(body["line_counts"]["unparsed"], 1);
+        assert_eq!(body["line_counts"]["unparsed_samples"], serde_json::json!(["noise"]));
+        assert_eq!(body["sample_records"][0]["timestamp"], "2025-06-02T13:28:00Z");
+    }
+
+    #[test]
+    fn sub_second_timestamps_are_counted_at_second_precision() {
+        let whole = Utc.with_ymd_and_hms(2025, 6, 2, 13, 28, 0).unwrap();
+        let records = vec![
+            record(72.0, whole),
+            record(73.0, whole + chrono::Duration::milliseconds(500)),
+            record(74.0, whole + chrono::Duration::milliseconds(1250)),
+        ];
+        let mut warnings = Vec::new();
+        assert_eq!(count_truncated(&records, Precision::Seconds, &mut warnings), 2);
+        assert_eq!(warnings.len(), 1);
+
+        let mut warnings = Vec::new();
+        assert_eq!(count_truncated(&records, Precision::Millis, &mut warnings), 0);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn records_past_max_age_are_dropped() {
+        let now = Utc::now();
+        let records = vec![
+            record(72.0, now - chrono::Duration::days(100)),
+            record(73.0, now - chrono::Duration::days(80)),
+        ];
+        let mut warnings = Vec::new();
+        let (records, expired) = drop_expired(records, Some(chrono::Duration::days(90)), &mut warnings);
+        assert_eq!(expired, 1);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].value, 73.0);
+        assert!(warnings[0].starts_with("Dropped 1 records older than 90 days"));
+
+        let (records, expired) = drop_expired(records, None, &mut warnings);
+        assert_eq!((records.len(), expired), (1, 0));
+    }
+
+    #[test]
+    fn zone_thresholds_start_each_zone_inclusively() {
+        let zones = ZoneThresholds::parse("100, 140, 170").unwrap();
+        assert_eq!(zones.zone(99.9), "rest");
+        assert_eq!(zones.zone(100.0), "fat_burn");
+        assert_eq!(zones.zone(139.9), "fat_burn");
+        assert_eq!(zones.zone(140.0), "cardio");
+        assert_eq!(zones.zone(170.0), "peak");
+        assert!(ZoneThresholds::parse("100,170,140").is_err());
+        assert!(ZoneThresholds::parse("100,140").is_err());
+        assert!(ZoneThresholds::parse("100,abc,170").is_err());
+    }
+
+    // REDACT_VALUES is process-wide; tests that flip it hold this lock
+    static REDACT_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn min_timestamp_warning_masks_record_time_when_redacting() {
+        let _guard = REDACT_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let min = Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap();
+        let records = vec![record(72.0, Utc.with_ymd_and_hms(2024, 12, 31, 23, 0, 0).unwrap())];
+        let mut warnings = Vec::new();
+        REDACT_VALUES.store(true, Ordering::Relaxed);
+        let (_, rejected) = reject_before_min_timestamp(records, min, &mut warnings);
+        REDACT_VALUES.store(false, Ordering::Relaxed);
+        assert_eq!(rejected, 1);
+        assert!(warnings[0].starts_with("Rejected record at <redacted>"), "{}", warnings[0]);
+        assert!(!warnings[0].contains("2024-12-31"));
+    }
+
+    #[tokio::test]
+    async fn tz_test_resolves_local_and_dst_times() {
+        let (app_state, _write_rx) = test_state(test_config());
+        let url = serve_app(app_state).await;
+        let client = reqwest::Client::new();
+        let tz_test = |datetime: &'static str, tz: &'static str| {
+            client
+                .get(format!("{}/tz-test", url))
+                .query(&[("datetime", datetime), ("tz", tz)])
+                .send()
+        };
+
+        let body: serde_json::Value = tz_test("2025年6月2日 21:28", "Asia/Shanghai").await.unwrap().json().await.unwrap();
+        assert_eq!(body["format"], "chinese");
+        assert_eq!(body["utc"], "2025-06-02T13:28:00Z");
+        assert_eq!(body["offset_minutes"], 480);
+        assert_eq!(body["resolution"], "exact");
+
+        // 02:30 does not exist on the spring-forward night in New York
+        let body: serde_json::Value = tz_test("2025年3月9日 02:30", "America/New_York").await.unwrap().json().await.unwrap();
+        assert_eq!(body["resolution"], "nonexistent");
+
+        let response = tz_test("2025年6月2日 21:28", "Mars/Olympus").await.unwrap();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn shutdown_flushes_a_batch_still_waiting_for_the_minimum() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.greptime_url = greptime_url;
+        config.flush_min_records = 100;
+        config.flush_max_wait = Duration::from_secs(60);
+        let (app_state, write_rx) = test_state(config);
+        let (shutdown, flusher) = spawn_flusher(&app_state, write_rx);
+
+        let write = tokio::spawn({
+            let app_state = app_state.clone();
+            async move { enqueue_write(&app_state, vec!["heart_rate value=72 1".to_string()], Precision::Millis).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(requests.lock().unwrap().is_empty());
+
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), flusher).await.expect("flusher did not exit").unwrap();
+        assert!(write.await.unwrap().is_ok());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn each_line_tries_every_timestamp_format() {
+        let mut options = default_parse_options();
+        options.timestamp_formats = vec![TimestampFormat::IsoLocal, TimestampFormat::Chinese];
+        let parsed = parse_heart_rate_data("72\n2025年6月2日 21:28\n75\n2025-06-02 21:29:00\n", &options).unwrap();
+        let times: Vec<i64> = parsed.records.iter().map(|record| record.timestamp.timestamp()).collect();
+        assert_eq!(times, [1748870880, 1748870940]);
+
+        // Formats left out of the list are not tried
+        options.timestamp_formats = vec![TimestampFormat::Chinese];
+        let parsed = parse_heart_rate_data("72\n2025年6月2日 21:28\n75\n2025-06-02 21:29:00\n", &options).unwrap();
+        assert_eq!(parsed.records.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fallback_writes_keep_the_proxy_unready() {
+        let (greptime_url, requests) = spawn_mock(|_| (StatusCode::BAD_REQUEST, "rejected".to_string())).await;
+        let path = temp_path("fallback.lp");
+        let mut config = test_config();
+        config.fallback_to_file = true;
+        config.sink_path = path.to_string_lossy().into_owned();
+        config.write_max_retries = 0;
+        let (url, app_state, _shutdown) = serve_writing_app(config, &greptime_url).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/heart-rate", url))
+            .body("72\n2025年6月2日 21:28\n")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains(" 1748870880000"), "{}", written);
+        assert_eq!(app_state.metrics.fallback_lines_total.get(), 1);
+
+        let response = reqwest::get(format!("{}/health/ready", url)).await.unwrap();
+        assert_eq!(response.status(), 503);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["last_error"]["message"].as_str().unwrap().contains("fallback file"), "{}", body);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn precision_seconds_writes_second_timestamps() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+
+        let (status, _) = post_json(&format!("{}/heart-rate?precision=s", url), "72\n2025年6月2日 21:28\n").await;
+        assert_eq!(status, 200);
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].uri.ends_with("&precision=s"), "{}", requests[0].uri);
+        assert_eq!(requests[0].body.trim_end(), "heart_rate,device_id=apple-watch value=72.00 1748870880");
+    }
+
+    #[test]
+    fn bare_values_get_evenly_spaced_synthesized_timestamps() {
+        let end = Utc.with_ymd_and_hms(2025, 6, 2, 13, 0, 0).unwrap();
+        let mut options = default_parse_options();
+        options.synthesize_timestamps = Some(SynthesizedTimestamps { interval: chrono::Duration::seconds(60), end });
+        let parsed = parse_heart_rate_data("70\n71\n72\n73\n74\n", &options).unwrap();
+        let times: Vec<DateTime<Utc>> = parsed.records.iter().map(|record| record.timestamp).collect();
+        let expected: Vec<DateTime<Utc>> = (0..5).rev().map(|i| end - chrono::Duration::minutes(i)).collect();
+        assert_eq!(times, expected);
+        assert_eq!(parsed.records[0].value, 70.0);
+        assert_eq!(parsed.records[4].value, 74.0);
+    }
+
+    #[test]
+    fn measurement_names_are_validated_and_overridable() {
+        assert!(validate_measurement("heart_rate_v2").is_ok());
+        assert!(validate_measurement("").is_err());
+        assert!(validate_measurement(&"m".repeat(65)).is_err());
+        assert!(validate_measurement("heart-rate").is_err());
+        assert!(validate_measurement("heart rate").is_err());
+        assert!(validate_measurement("2heart").is_err());
+
+        let config = test_config();
+        assert_eq!(request_measurement(&config, None, None, "w1"), DEFAULT_MEASUREMENT);
+        assert_eq!(request_measurement(&config, Some("hr_test"), None, "w1"), "hr_test");
+        assert_eq!(request_measurement(&config, Some("hr_test"), Some(true), "w-1"), "hr_test_w_1");
+    }
+
+    #[test]
+    fn catalog_schema_database_is_encoded_in_the_write_url() {
+        let mut config = test_config();
+        config.greptime_db = "my_catalog-my schema".to_string();
+        assert_eq!(
+            write_url(&config, "http://db:4000", Precision::Millis),
+            "http://db:4000/v1/influxdb/api/v2/write?db=my_catalog-my%20schema&precision=ms"
+        );
+        assert_eq!(encode_query_value("a&b=c"), "a%26b%3Dc");
+    }
+
+    #[test]
+    fn recently_written_timestamps_are_skipped_per_device() {
+        let mut config = test_config();
+        config.dedup_window = Some(Duration::from_secs(60));
+        let (app_state, _write_rx) = test_state(config);
+        let written = records_per_minute(&[72.0])[0].timestamp;
+        app_state.remember_written("w1", [written]);
+
+        let (kept, skipped) = app_state.drop_recently_written("w1", records_per_minute(&[72.0, 73.0]));
+        assert_eq!(skipped, 1);
+        assert_eq!(kept[0].value, 73.0);
+
+        // Other devices and disabled dedup are unaffected
+        assert_eq!(app_state.drop_recently_written("w2", records_per_minute(&[72.0, 73.0])).1, 0);
+        let (app_state, _write_rx) = test_state(test_config());
+        app_state.remember_written("w1", [written]);
+        assert_eq!(app_state.drop_recently_written("w1", records_per_minute(&[72.0, 73.0])).1, 0);
+    }
+
+    #[test]
+    fn success_message_uses_the_template() {
+        let mut config = test_config();
+        assert_eq!(success_message(&config, 3), "Successfully processed 3 heart rate records");
+        config.success_message_template = Some("已写入 {count} 条 ({count})".to_string());
+        assert_eq!(success_message(&config, 3), "已写入 3 条 (3)");
+    }
+
+    #[tokio::test]
+    async fn only_written_requests_start_the_debounce_window() {
+        let (greptime_url, _requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.write_debounce = Some(Duration::from_secs(60));
+        let (url, _app_state, _shutdown) = serve_writing_app(config, &greptime_url).await;
+        let write_url = format!("{}/heart-rate?device_id=w1", url);
+
+        // Nothing written, so the corrected resend goes through
+        let (_, body) = post_json(&write_url, "hello\n").await;
+        assert_eq!(body["processed_count"], 0);
+        let (status, _) = post_json(&write_url, "72\n2025年6月2日 21:28\n").await;
+        assert_eq!(status, 200);
+
+        let response = reqwest::Client::new().post(&write_url).body("73\n2025年6月2日 21:29\n").send().await.unwrap();
+        assert_eq!(response.status(), 429);
+        let (status, _) = post_json(&format!("{}/heart-rate?device_id=w2", url), "73\n2025年6月2日 21:29\n").await;
+        assert_eq!(status, 200);
+    }
+
+    #[tokio::test]
+    async fn simultaneous_requests_share_one_debounce_window() {
+        let (greptime_url, _requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.write_debounce = Some(Duration::from_secs(60));
+        let (url, _app_state, _shutdown) = serve_writing_app(config, &greptime_url).await;
+        let write_url = format!("{}/heart-rate?device_id=w1", url);
+
+        let client = reqwest::Client::new();
+        let (first, second) = tokio::join!(
+            client.post(&write_url).body("72\n2025年6月2日 21:28\n").send(),
+            client.post(&write_url).body("73\n2025年6月2日 21:29\n").send(),
+        );
+        let mut statuses = [first.unwrap().status().as_u16(), second.unwrap().status().as_u16()];
+        statuses.sort();
+        assert_eq!(statuses, [200, 429]);
+    }
+
+    #[test]
+    fn opentsdb_points_carry_metric_tags_and_millisecond_timestamps() {
+        let points = opentsdb_data_points("heart_rate,device_id=w\\ 1 value=72.5,offset_minutes=480i,note=\"x\" 1748870880000000", Precision::Micros);
+        assert_eq!(
+            serde_json::Value::from(points),
+            serde_json::json!([
+                { "metric": "heart_rate", "timestamp": 1748870880000i64, "value": 72.5, "tags": { "device_id": "w 1" } },
+                { "metric": "heart_rate_offset_minutes", "timestamp": 1748870880000i64, "value": 480.0, "tags": { "device_id": "w 1" } },
+            ])
+        );
+        assert!(opentsdb_data_points("heart_rate value=72", Precision::Millis).is_empty());
+    }
+
+    #[tokio::test]
+    async fn opentsdb_put_fails_when_no_line_converts() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.greptime_url = greptime_url;
+        let (app_state, _write_rx) = test_state(config);
+
+        let result = OpenTsdbWriter.put(&app_state, vec!["heart_rate value=72".to_string()], Precision::Millis).await;
+        assert!(matches!(result, Err(WriteError::Unconvertible(_))), "{:?}", result);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn dry_run_preview_matches_the_configured_sink() {
+        let lines = vec!["heart_rate value=72 1748870880000".to_string()];
+        let mut config = test_config();
+        config.greptime_url = "http://db:4000".to_string();
+        config.write_backend = "opentsdb".to_string();
+        let preview = preview_write_request(&config, &lines, Precision::Millis);
+        assert_eq!(preview.url, "http://db:4000/v1/opentsdb/api/put?db=heartbeat_test");
+        assert_eq!(preview.headers["Content-Type"], "application/json");
+        assert!(preview.body_preview.starts_with(r#"[{"metric":"heart_rate""#), "{}", preview.body_preview);
+
+        config.write_sink = "file".to_string();
+        config.sink_path = "/tmp/heart_rate.lp".to_string();
+        let preview = preview_write_request(&config, &lines, Precision::Millis);
+        assert_eq!((preview.method.as_str(), preview.url.as_str()), ("APPEND", "/tmp/heart_rate.lp"));
+        assert!(preview.headers.is_empty());
+        assert_eq!(preview.body_preview, "heart_rate value=72 1748870880000\n");
+    }
+
+    #[test]
+    fn extra_tags_are_capped_at_max_tags() {
+        let mut config = test_config();
+        config.max_tags = 2;
+        assert_eq!(
+            parse_extra_tags(&config, Some("site:berlin, activity:run")).unwrap(),
+            [("site".to_string(), "berlin".to_string()), ("activity".to_string(), "run".to_string())]
+        );
+        let (status, message) = parse_extra_tags(&config, Some("a:1,b:2,c:3")).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(message, "Invalid tags: 3 tags given, at most 2 are allowed");
+        assert!(parse_extra_tags(&config, Some("device_id:x")).is_err());
+    }
+
+    #[test]
+    fn decimal_comma_values_parse_with_the_option() {
+        let mut options = default_parse_options();
+        options.decimal_comma = true;
+        let parsed = parse_heart_rate_data("72,5\n2025年6月2日 21:28\n", &options).unwrap();
+        assert_eq!(parsed.records[0].value, 72.5);
+
+        assert_eq!(parse_number("72,5", false), None);
+        assert_eq!(parse_number("72,5,3", true), None);
+        assert_eq!(parse_number("72.5", true), Some(72.5));
+    }
+
+    #[tokio::test]
+    async fn raw_retention_waits_for_failed_rollups() {
+        let rollup_fails = Arc::new(AtomicBool::new(true));
+        let (greptime_url, requests) = spawn_mock({
+            let rollup_fails = rollup_fails.clone();
+            move |request: &MockRequest| {
+                if request.body.contains("DELETE") {
+                    (StatusCode::OK, r#"{"output":[{"affectedrows":5}]}"#.to_string())
+                } else if rollup_fails.load(Ordering::Relaxed) {
+                    (StatusCode::INTERNAL_SERVER_ERROR, "unavailable".to_string())
+                } else {
+                    (StatusCode::OK, sql_result(serde_json::json!([])))
+                }
+            }
+        })
+        .await;
+        let mut config = test_config();
+        config.greptime_url = greptime_url;
+        config.raw_retention_days = Some(1);
+        let (app_state, _write_rx) = test_state(config);
+        let mut pending_days = std::collections::BTreeSet::new();
+        let day = |d| chrono::NaiveDate::from_ymd_opt(2025, 6, d).unwrap();
+        let deletes = |requests: &MockRequests| requests.lock().unwrap().iter().filter(|r| r.body.contains("DELETE")).count();
+
+        rollup_and_expire(&app_state, day(2), &mut pending_days).await;
+        assert_eq!(pending_days.iter().copied().collect::<Vec<_>>(), [day(1)]);
+        assert_eq!(deletes(&requests), 1);
+
+        // June 1 is now before the cutoff and still not summarized
+        rollup_and_expire(&app_state, day(3), &mut pending_days).await;
+        assert_eq!(pending_days.len(), 2);
+        assert_eq!(deletes(&requests), 1);
+
+        rollup_fails.store(false, Ordering::Relaxed);
+        rollup_and_expire(&app_state, day(4), &mut pending_days).await;
+        assert!(pending_days.is_empty());
+        assert_eq!(deletes(&requests), 2);
+    }
+
+    #[tokio::test]
+    async fn writes_use_the_configured_http_method() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.write_method = reqwest::Method::PUT;
+        let (url, _app_state, _shutdown) = serve_writing_app(config, &greptime_url).await;
+
+        let (status, _) = post_json(&format!("{}/heart-rate", url), "72\n2025年6月2日 21:28\n").await;
+        assert_eq!(status, 200);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].method, "PUT");
+        assert!(requests[0].uri.starts_with("/v1/influxdb/api/v2/write?"), "{}", requests[0].uri);
+    }
+
+    #[tokio::test]
+    async fn warnings_beyond_max_warnings_are_counted() {
+        let (greptime_url, _requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.max_warnings = 1;
+        config.min_timestamp = Utc.with_ymd_and_hms(2025, 6, 2, 13, 29, 0).unwrap();
+        let (url, _app_state, _shutdown) = serve_writing_app(config, &greptime_url).await;
+
+        let body = "72\n2025年6月2日 21:26\n73\n2025年6月2日 21:27\n74\n2025年6月2日 21:28\n75\n2025年6月2日 21:29\n";
+        let (_, body) = post_json(&format!("{}/heart-rate", url), body).await;
+        assert_eq!(body["processed_count"], 1);
+        assert_eq!(body["warnings"].as_array().unwrap().len(), 1);
+        assert_eq!(body["omitted_warnings"], 2);
+    }
+
+    #[tokio::test]
+    async fn utf16le_uploads_are_decoded() {
+        let text = "72\r\n2025年6月2日 21:28\r\n";
+        let mut body = vec![0xFF, 0xFE];
+        body.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        assert_eq!(decode_body(&body).unwrap(), text);
+        // A truncated code unit is rejected rather than replaced
+        assert_eq!(decode_body(&body[..body.len() - 1]).unwrap_err().0, StatusCode::BAD_REQUEST);
+
+        let (app_state, _write_rx) = test_state(test_config());
+        let url = serve_app(app_state).await;
+        let response = reqwest::Client::new()
+            .post(format!("{}/heart-rate?dry_run=true", url))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["dry_run"]["body_preview"], "heart_rate,device_id=apple-watch value=72.00 1748870880000");
+    }
+
+    #[tokio::test]
+    async fn json_bodies_use_the_configured_keys() {
+        let mut config = test_config();
+        config.value_keys = vec!["pulse".to_string()];
+        config.time_keys = vec!["at".to_string()];
+        let (app_state, _write_rx) = test_state(config);
+        let url = serve_app(app_state).await;
+        let body = r#"[{"pulse": 72, "at": "2025-06-02T21:28:00+08:00"}, {"value": 75, "at": "2025-06-02T21:29:00+08:00"}]"#;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/diagnose", url))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["record_count"], 1, "{}", body);
+        assert_eq!(body["sample_records"][0]["value"], 72.0);
+        assert_eq!(body["sample_records"][0]["timestamp"], "2025-06-02T13:28:00Z");
+    }
+
+    #[tokio::test]
+    async fn reload_applies_config_file_changes_without_touching_the_environment() {
+        let path = temp_path("reload.env");
+        std::fs::write(&path, "# limits\nMAX_TAGS=3\n\nSUCCESS_MESSAGE_TEMPLATE = wrote {count}\nAPI_TOKEN=secret\n").unwrap();
+        let source = ConfigSource::from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(Config::from_source(&source).max_tags, 3);
+        assert!(std::env::var("MAX_TAGS").is_err());
+
+        let (mut app_state, _write_rx) = test_state(Config::from_source(&source));
+        app_state.config_file = Some(path.to_string_lossy().into_owned());
+        let url = serve_app(app_state.clone()).await;
+
+        std::fs::write(&path, "MAX_TAGS=5\nAPI_TOKEN=secret\n").unwrap();
+        let response = reqwest::Client::new().post(format!("{}/reload", url)).bearer_auth("secret").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(app_state.config().max_tags, 5);
+        // Removed from the file, so back to the default
+        assert_eq!(app_state.config().success_message_template, None);
+
+        std::fs::write(&path, "not a setting\n").unwrap();
+        let response = reqwest::Client::new().post(format!("{}/reload", url)).bearer_auth("secret").send().await.unwrap();
+        assert_eq!(response.status(), 500);
+        assert_eq!(app_state.config().max_tags, 5);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn returned_records_carry_source_lines_in_json_and_csv() {
+        let (greptime_url, _requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+        let body = "noise\n72\n2025年6月2日 21:28\n";
+
+        let (_, json) = post_json(&format!("{}/heart-rate?return_records=true&with_source_line=true", url), body).await;
+        assert_eq!(json["records"][0]["source_line"], 2);
+
+        let csv = reqwest::Client::new()
+            .post(format!("{}/heart-rate?return_records=true&with_source_line=true&format=csv", url))
+            .body(body)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(csv, "timestamp,value,offset_minutes,delta,confidence,source_line\n2025-06-02T13:28:00Z,72,480,,,2\n");
+    }
+
+    #[tokio::test]
+    async fn stream_batches_never_split_a_pair() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.stream_batch_size = 1;
+        let (url, _app_state, _shutdown) = serve_writing_app(config, &greptime_url).await;
+
+        // Three body lines per pair, so every pair straddles a one-line boundary of the body
+        let body = "72\n2025年6月2日 21:28\nnoise\n73\n2025年6月2日 21:29\nnoise\n";
+        let events = post_stream(&format!("{}/heart-rate/stream?device_id=w1", url), body).await;
+        assert_eq!(events.last().unwrap().1["batches"], 2);
+
+        let requests = requests.lock().unwrap();
+        let bodies: Vec<&str> = requests.iter().map(|request| request.body.trim_end()).collect();
+        assert_eq!(
+            bodies,
+            [
+                "heart_rate,device_id=w1 value=72.00 1748870880000",
+                "heart_rate,device_id=w1 value=73.00 1748870940000",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn small_batches_flush_at_the_max_wait_deadline() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.greptime_url = greptime_url;
+        config.flush_min_records = 100;
+        config.flush_max_wait = Duration::from_millis(300);
+        let (app_state, write_rx) = test_state(config);
+        let (_shutdown, _flusher) = spawn_flusher(&app_state, write_rx);
+
+        let started = Instant::now();
+        let result = enqueue_write(&app_state, vec!["heart_rate value=72 1".to_string()], Precision::Millis).await;
+        assert!(result.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(300), "{:?}", started.elapsed());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn readiness_reports_the_last_write_error_until_a_write_succeeds() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let (greptime_url, _requests) = spawn_mock({
+            let failing = failing.clone();
+            move |_: &MockRequest| {
+                if failing.load(Ordering::Relaxed) {
+                    (StatusCode::BAD_REQUEST, "table schema mismatch".to_string())
+                } else {
+                    (StatusCode::NO_CONTENT, String::new())
+                }
+            }
+        })
+        .await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+        let ready = || async {
+            let body: serde_json::Value = reqwest::get(format!("{}/health/ready", url)).await.unwrap().json().await.unwrap();
+            body
+        };
+
+        let (status, _) = post_json(&format!("{}/heart-rate", url), "72\n2025年6月2日 21:28\n").await;
+        assert_eq!(status, 400);
+        let body = ready().await;
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["last_error"]["message"], "GreptimeDB error: table schema mismatch");
+        assert!(body["last_error"]["at"].is_string());
+
+        failing.store(false, Ordering::Relaxed);
+        let (status, _) = post_json(&format!("{}/heart-rate", url), "72\n2025年6月2日 21:28\n").await;
+        assert_eq!(status, 200);
+        let body = ready().await;
+        assert_eq!(body["ready"], true);
+        assert!(body.get("last_error").is_none());
+    }
+
+    #[test]
+    fn combined_timestamp_and_value_lines_parse() {
+        assert_eq!(split_combined_line("2025年6月2日 21:28 72"), Some(("2025年6月2日 21:28", "72")));
+        assert_eq!(split_combined_line("72, 2025-06-02T21:28:00Z"), Some(("2025-06-02T21:28:00Z", "72")));
+        assert_eq!(split_combined_line("72"), None);
+
+        let body = "2025年6月2日 21:28 72\n2025-06-02T13:29:00Z;75.5\n";
+        let parsed = parse_heart_rate_data(body, &default_parse_options()).unwrap();
+        let records: Vec<(i64, f64)> = parsed.records.iter().map(|record| (record.timestamp.timestamp(), record.value)).collect();
+        assert_eq!(records, [(1748870880, 72.0), (1748870940, 75.5)]);
+    }
+
+    #[tokio::test]
+    async fn responses_are_gzipped_when_enabled_and_accepted() {
+        let get_tz_test = |url: String| async move {
+            reqwest::Client::new()
+                .get(format!("{}/tz-test", url))
+                .query(&[("datetime", "2025年6月2日 21:28")])
+                .header("Accept-Encoding", "gzip")
+                .send()
+                .await
+                .unwrap()
+        };
+
+        let mut config = test_config();
+        config.compress_responses = true;
+        let (app_state, _write_rx) = test_state(config);
+        let response = get_tz_test(serve_app(app_state).await).await;
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.bytes().await.unwrap()[..2], [0x1f, 0x8b]);
+
+        let (app_state, _write_rx) = test_state(test_config());
+        let response = get_tz_test(serve_app(app_state).await).await;
+        assert!(response.headers().get("content-encoding").is_none());
+    }
+
+    #[tokio::test]
+    async fn measurements_outside_the_allowlist_are_forbidden() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.allowed_measurements = vec!["heart_rate".to_string(), "hr_test".to_string()];
+        let (url, _app_state, _shutdown) = serve_writing_app(config, &greptime_url).await;
+        let post = |measurement: &'static str| {
+            reqwest::Client::new()
+                .post(format!("{}/heart-rate?measurement={}", url, measurement))
+                .body("72\n2025年6月2日 21:28\n")
+                .send()
+        };
+
+        let response = post("other").await.unwrap();
+        assert_eq!(response.status(), 403);
+        assert_eq!(response.text().await.unwrap(), "Measurement 'other' is not allowed; allowed: heart_rate, hr_test");
+        assert_eq!(post("bad-name").await.unwrap().status(), 400);
+        assert!(requests.lock().unwrap().is_empty());
+
+        assert_eq!(post("hr_test").await.unwrap().status(), 200);
+        assert!(requests.lock().unwrap()[0].body.starts_with("hr_test,"));
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_inflight_limit_get_503() {
+        let greptime_url = serve_hanging().await;
+        let mut config = test_config();
+        config.max_inflight_requests = Some(1);
+        let (url, _app_state, _shutdown) = serve_writing_app(config, &greptime_url).await;
+
+        // Held in flight by the write that never completes
+        let write_url = format!("{}/heart-rate", url);
+        let _stuck = tokio::spawn(async move { post_json(&write_url, "72\n2025年6月2日 21:28\n").await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let response = reqwest::get(format!("{}/check-auth", url)).await.unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.text().await.unwrap(), "Too many requests in flight, try again later");
+
+        // Probes stay answerable while saturated; readiness reports its own 503 until a write succeeds
+        assert_eq!(reqwest::get(format!("{}/health", url)).await.unwrap().status(), 200);
+        let response = reqwest::get(format!("{}/health/ready", url)).await.unwrap();
+        assert_ne!(response.text().await.unwrap(), "Too many requests in flight, try again later");
+    }
+
+    #[tokio::test]
+    async fn ingest_time_is_the_receive_time() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let (url, _app_state, _shutdown) = serve_writing_app(test_config(), &greptime_url).await;
+
+        let before = Utc::now().timestamp_millis();
+        let (status, _) = post_json(&format!("{}/heart-rate?with_ingest_time=true", url), "72\n2025年6月2日 21:28\n").await;
+        let after = Utc::now().timestamp_millis();
+        assert_eq!(status, 200);
+
+        let body = requests.lock().unwrap()[0].body.clone();
+        let ingested_at: i64 = body
+            .split_once(",ingested_at=")
+            .and_then(|(_, rest)| rest.split_once("i "))
+            .and_then(|(millis, _)| millis.parse().ok())
+            .unwrap_or_else(|| panic!("no ingested_at in {}", body));
+        assert!((before..=after).contains(&ingested_at), "{} not in {}..={}", ingested_at, before, after);
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_stop_at_the_write_deadline() {
+        let (greptime_url, requests) = spawn_mock(|_| (StatusCode::SERVICE_UNAVAILABLE, "busy".to_string())).await;
+        let mut config = test_config();
+        config.write_max_retries = 1000;
+        config.retry_base_delay = Duration::from_millis(50);
+        config.retry_max_delay = Duration::from_millis(50);
+        let (app_state, _write_rx) = test_state(config);
+
+        let started = Instant::now();
+        let deadline = Some(started + Duration::from_millis(300));
+        let result = write_lines_with_retry(&app_state, &greptime_url, "heart_rate value=72 1".to_string(), Precision::Millis, deadline).await;
+        assert!(matches!(result, Err(WriteError::Status { status: 503, .. })), "{:?}", result);
+        // No retry starts past the deadline; only the attempt in flight may overrun it
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+        let attempts = requests.lock().unwrap().len();
+        assert!((2..1000).contains(&attempts), "{} attempts", attempts);
+    }
+
+    #[test]
+    fn device_id_case_folds_variants_together() {
+        assert_eq!(DeviceIdCase::Lower.apply("Watch1"), "watch1");
+        assert_eq!(DeviceIdCase::Upper.apply("Watch1"), "WATCH1");
+        assert_eq!(DeviceIdCase::None.apply("Watch1"), "Watch1");
+        assert_eq!(DeviceIdCase::parse(" LOWER "), Some(DeviceIdCase::Lower));
+        assert_eq!(DeviceIdCase::parse("title"), None);
+
+        let mut config = test_config();
+        config.device_id_case = DeviceIdCase::Lower;
+        assert_eq!(normalize_device_id(&config, "Watch1").unwrap(), "watch1");
+    }
+
+    #[test]
+    fn parse_number_accepts_only_plain_decimals() {
+        assert_eq!(parse_number("72.", false), Some(72.0));
+        assert_eq!(parse_number(".5", false), Some(0.5));
+        assert_eq!(parse_number("072", false), Some(72.0));
+        assert_eq!(parse_number("+72", false), Some(72.0));
+        assert_eq!(parse_number("-72.5", false), Some(-72.5));
+        for rejected in ["7e1", "7E1", "1e400", "inf", "-inf", "Infinity", "nan", "NaN", ".", "", "1.2.3", "72 bpm"] {
+            assert_eq!(parse_number(rejected, false), None, "{:?}", rejected);
+        }
+    }
+
+    #[tokio::test]
+    async fn check_auth_reports_whether_greptime_accepts_the_credentials() {
+        // base64("alice:secret")
+        let (greptime_url, requests) = spawn_mock(|request: &MockRequest| {
+            match request.authorization.as_deref() {
+                Some("Basic YWxpY2U6c2VjcmV0") => (StatusCode::OK, sql_result(serde_json::json!([[1]]))),
+                _ => (StatusCode::UNAUTHORIZED, "auth failed".to_string()),
+            }
+        })
+        .await;
+        let check_auth = |password: &'static str| {
+            let mut config = test_config();
+            config.greptime_url = greptime_url.clone();
+            config.greptime_username = Some("alice".to_string());
+            config.greptime_password = Some(password.to_string());
+            async move {
+                let (app_state, _write_rx) = test_state(config);
+                let url = serve_app(app_state).await;
+                let response = reqwest::get(format!("{}/check-auth", url)).await.unwrap();
+                assert_eq!(response.status(), 200);
+                response.json::<serde_json::Value>().await.unwrap()
+            }
+        };
+
+        let body = check_auth("secret").await;
+        assert_eq!(body["authenticated"], true);
+        assert_eq!(body["greptime_status"], 200);
+
+        let body = check_auth("wrong").await;
+        assert_eq!(body["authenticated"], false);
+        assert_eq!(body["greptime_status"], 401);
+        assert_eq!(body["message"], "GreptimeDB rejected the credentials");
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|request| request.method == "POST" && request.uri == "/v1/sql"));
+    }
+
+    #[test]
+    fn path_like_device_ids_are_rejected_or_sanitized() {
+        let mut config = test_config();
+        assert_eq!(normalize_device_id(&config, "../etc").unwrap(), "../etc");
+
+        config.device_id_policy = DeviceIdPolicy::Reject;
+        let (status, message) = normalize_device_id(&config, "../etc").unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message.starts_with("Invalid device_id '../etc'"), "{}", message);
+        assert_eq!(normalize_device_id(&config, "watch-1_a").unwrap(), "watch-1_a");
+
+        config.device_id_policy = DeviceIdPolicy::Sanitize;
+        assert_eq!(normalize_device_id(&config, "../etc").unwrap(), "___etc");
+        assert_eq!(normalize_device_id(&config, "a/b c").unwrap(), "a_b_c");
+    }
+
+    #[test]
+    fn labeled_text_lines_use_their_own_label_keys() {
+        let body = "Pulse = 72\nwhen=2025-06-02T21:28:00+08:00\n";
+        let mut options = default_parse_options();
+        assert!(parse_heart_rate_data(body, &options).unwrap().records.is_empty());
+
+        options.value_label_keys = vec!["pulse".to_string()];
+        options.time_label_keys = vec!["when".to_string()];
+        let parsed = parse_heart_rate_data(body, &options).unwrap();
+        let records: Vec<(i64, f64)> = parsed.records.iter().map(|record| (record.timestamp.timestamp(), record.value)).collect();
+        assert_eq!(records, [(1748870880, 72.0)]);
+
+        // JSON keys no longer affect text parsing
+        let mut options = default_parse_options();
+        options.value_keys = vec!["pulse".to_string()];
+        options.time_keys = vec!["when".to_string()];
+        assert!(parse_heart_rate_data(body, &options).unwrap().records.is_empty());
+        assert_eq!(parse_heart_rate_data("bpm=72\ntime=2025-06-02T21:28:00+08:00\n", &options).unwrap().records.len(), 1);
+    }
+
+    #[test]
+    fn line_labels_decide_value_or_timestamp() {
+        let options = default_parse_options();
+        let parsed = parse_heart_rate_data("time=2025-06-02T21:28:00+08:00\nbpm=72\n", &options).unwrap();
+        let records: Vec<(i64, f64)> = parsed.records.iter().map(|record| (record.timestamp.timestamp(), record.value)).collect();
+        assert_eq!(records, [(1748870880, 72.0)]);
+
+        // Each label names the wrong kind, so neither line counts
+        let parsed = parse_heart_rate_data("bpm=2025-06-02T21:28:00+08:00\ntime=72\n", &options).unwrap();
+        assert!(parsed.records.is_empty());
+        assert_eq!(parsed.line_stats.values, 0);
+        assert_eq!(parsed.line_stats.unparsed, 2);
+    }
+
+    #[test]
+    fn implausible_jumps_drop_the_spike_not_its_neighbours() {
+        let values = |records: &[HeartRateRecord]| records.iter().map(|record| record.value).collect::<Vec<_>>();
+        let mut warnings = Vec::new();
+
+        let (kept, dropped) = drop_implausible_jumps(records_per_minute(&[160.0, 70.0, 71.0, 72.0]), Some(1.0), &mut warnings);
+        assert_eq!((values(&kept), dropped), (vec![70.0, 71.0, 72.0], 1));
+
+        let (kept, dropped) = drop_implausible_jumps(records_per_minute(&[70.0, 160.0, 71.0, 72.0]), Some(1.0), &mut warnings);
+        assert_eq!((values(&kept), dropped), (vec![70.0, 71.0, 72.0], 1));
+
+        // A lasting step change isn't a spike
+        let (kept, dropped) = drop_implausible_jumps(records_per_minute(&[70.0, 70.0, 150.0, 150.0]), Some(1.0), &mut warnings);
+        assert_eq!((values(&kept), dropped), (vec![70.0, 70.0, 150.0, 150.0], 0));
+    }
+
+    #[test]
+    fn jump_warning_masks_record_time_when_redacting() {
+        let _guard = REDACT_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut warnings = Vec::new();
+        REDACT_VALUES.store(true, Ordering::Relaxed);
+        let (_, dropped) = drop_implausible_jumps(records_per_minute(&[70.0, 160.0]), Some(1.0), &mut warnings);
+        REDACT_VALUES.store(false, Ordering::Relaxed);
+        assert_eq!(dropped, 1);
+        assert_eq!(warnings[0], "Dropped record at <redacted>: jump of <redacted> BPM in 60s exceeds 1 BPM/s");
+    }
+
+    #[test]
+    fn chinese_timestamp_converts_to_epoch_millis() {
+        let (time, resolution, format) = parse_any_timestamp("2025年6月2日 21:28", None, &TimestampFormat::ALL).unwrap();
+        assert_eq!((resolution, format), (LocalResolution::Exact, TimestampFormat::Chinese));
+        let time = time.with_timezone(&Utc);
+        assert_eq!(Precision::Millis.timestamp(&time), 1748870880000);
+        assert_eq!(Precision::Seconds.timestamp(&time), 1748870880);
+        assert_eq!(Precision::Nanos.timestamp(&time), 1748870880000000000);
+    }
+
+    #[tokio::test]
+    async fn device_deletion_is_forbidden_without_an_api_token() {
+        let (greptime_url, requests) = spawn_ok_mock().await;
+        let mut config = test_config();
+        config.greptime_url = greptime_url;
+        let (app_state, _write_rx) = test_state(config);
+        let url = serve_app(app_state).await;
+
+        let response = reqwest::Client::new()
+            .delete(format!("{}/device/w1?start=2025-06-01T00:00:00Z&end=2025-06-03T00:00:00Z", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reload_is_forbidden_without_an_api_token() {
+        let path = temp_path("reload-forbidden.env");
+        std::fs::write(&path, "MAX_TAGS=3\n").unwrap();
+        let (mut app_state, _write_rx) = test_state(test_config());
+        app_state.config_file = Some(path.to_string_lossy().into_owned());
+        let max_tags = app_state.config().max_tags;
+        let url = serve_app(app_state.clone()).await;
+
+        let response = reqwest::Client::new().post(format!("{}/reload", url)).send().await.unwrap();
+        assert_eq!(response.status(), 403);
+        assert_eq!(app_state.config().max_tags, max_tags);
+        std::fs::remove_file(path).unwrap();
+    }
+}